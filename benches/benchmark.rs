@@ -2,6 +2,8 @@ use criterion::{black_box, criterion_group, criterion_main, Criterion};
 use rand::rngs::mock::StepRng;
 use rand::rngs::SmallRng;
 use rand::{Rng, SeedableRng};
+use rust_allocator::replay::{replay, ReplayTarget};
+use rust_allocator::trace::{read_trace, TraceOp, TraceRecord};
 use shuffle::fy::FisherYates;
 use shuffle::shuffler::Shuffler;
 
@@ -11,7 +13,7 @@ fn _fragment_memory() {
     let h = 20000;
 
     let mut prev: *mut u8 = std::ptr::null_mut();
-    (0..h).into_iter().for_each(|x| {
+    (0..h).for_each(|x| {
         let m = rust_allocator::alloc(rng.gen_range(1..100));
         if x != 0 && rng1.gen_range(1..=100) > 80 {
             rust_allocator::dealloc(prev);
@@ -45,7 +47,58 @@ fn alloc_benchmark_small_inp(c: &mut Criterion) {
             }
         })
     });
+
+    println!("{}", rust_allocator::fragmentation());
+}
+
+// Made up trace with the same shape as _fragment_memory, used when REPLAY_TRACE isn't set
+fn synthetic_trace() -> Vec<TraceRecord> {
+    let mut rng = SmallRng::seed_from_u64(42);
+    let mut records = vec![];
+    let mut live = vec![];
+
+    for addr in 1..=20000u64 {
+        let size = rng.gen_range(1..100);
+        records.push(TraceRecord {
+            op: TraceOp::Alloc,
+            size,
+            addr,
+            new_addr: 0,
+            time_ns: 0,
+        });
+        live.push((addr, size));
+        if rng.gen_range(1..=100) > 80 {
+            let (addr, size) = live.swap_remove(rng.gen_range(0..live.len()));
+            records.push(TraceRecord {
+                op: TraceOp::Dealloc,
+                size,
+                addr,
+                new_addr: 0,
+                time_ns: 0,
+            });
+        }
+    }
+    records
+}
+
+fn replay_benchmark(c: &mut Criterion) {
+    let records = match std::env::var("REPLAY_TRACE") {
+        Ok(path) => read_trace(&mut std::fs::File::open(path).unwrap()).unwrap(),
+        Err(_) => synthetic_trace(),
+    };
+
+    let mut group = c.benchmark_group("replay");
+    // A single next fit replay over a fragmented heap takes close to a second
+    group.sample_size(10);
+    for (name, target) in [
+        ("next fit", ReplayTarget::NextFit),
+        ("system", ReplayTarget::System),
+    ] {
+        println!("{:?}", replay(&records, target));
+        group.bench_function(name, |b| b.iter(|| replay(black_box(&records), target)));
+    }
+    group.finish();
 }
 
-criterion_group!(benches, alloc_benchmark_small_inp);
+criterion_group!(benches, alloc_benchmark_small_inp, replay_benchmark);
 criterion_main!(benches);
@@ -5,6 +5,7 @@
 
 extern char *alloc(unsigned long long);
 extern void dealloc(char *);
+extern unsigned long leak_report(void);
 
 int main() {
   int sz = 1;
@@ -35,7 +36,10 @@ int main() {
   /* write(1, s, strlen(s)); */
   /* } */
 
-  /* #ifndef LEAK */
-  /* dealloc(m); */
-  /* #endif */
+#ifndef LEAK
+  dealloc((char *)m);
+#endif
+
+  // Non zero exit status if anything is still allocated
+  return leak_report() != 0;
 }
@@ -0,0 +1,97 @@
+// Renders the pools of a heap as a block map.
+//
+// heapviz [--svg] [--width N] [--words-per-cell N] <dump.jsonl>
+//     reads a file written by heap_dump
+// heapviz [--svg] [--width N] [--words-per-cell N] --demo
+//     fragments the heap of this very process with random allocs/deallocs and renders it
+//
+// The ANSI map goes to stdout as is, with --svg an svg document is written instead.
+use std::process::exit;
+
+use rust_allocator::{alloc, dealloc, dump_current_heap, parse_dump, render_ansi, render_svg};
+
+const USAGE: &str =
+    "usage: heapviz [--svg] [--width N] [--words-per-cell N] (<dump.jsonl> | --demo)";
+
+// Same kind of workload as the one in benches/benchmark.rs, using a tiny LCG so that we don't
+// need rand outside of dev-dependencies
+fn fragment_memory() {
+    let mut state: u64 = 42;
+    let mut next = move || {
+        state = state
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        state >> 33
+    };
+
+    let mut live = vec![];
+    for _ in 0..20000 {
+        live.push(alloc(next() % 100 + 1));
+        if next() % 100 >= 80 {
+            let idx = next() as usize % live.len();
+            dealloc(live.swap_remove(idx));
+        }
+    }
+}
+
+fn main() {
+    let mut svg = false;
+    let mut width = None;
+    let mut words_per_cell = None;
+    let mut input = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut num = |name: &str| -> usize {
+            args.next().and_then(|v| v.parse().ok()).unwrap_or_else(|| {
+                eprintln!("{name} expects a number\n{USAGE}");
+                exit(2)
+            })
+        };
+        match arg.as_str() {
+            "--svg" => svg = true,
+            "--width" => width = Some(num("--width")),
+            "--words-per-cell" => words_per_cell = Some(num("--words-per-cell")),
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return;
+            }
+            _ => input = Some(arg),
+        }
+    }
+
+    let pools = match input.as_deref() {
+        None => {
+            eprintln!("{USAGE}");
+            exit(2)
+        }
+        Some("--demo") => {
+            fragment_memory();
+            dump_current_heap()
+        }
+        Some(path) => {
+            let parsed = std::fs::read_to_string(path)
+                .map_err(|e| e.to_string())
+                .and_then(|s| parse_dump(&s));
+            match parsed {
+                Ok(pools) => pools,
+                Err(e) => {
+                    eprintln!("heapviz: {path}: {e}");
+                    exit(1)
+                }
+            }
+        }
+    };
+
+    if svg {
+        print!("{}", render_svg(&pools, width.unwrap_or(1200)));
+    } else {
+        let width = width.unwrap_or(128);
+        // By default every pool takes up at most ~32 rows
+        let words_per_cell = words_per_cell.unwrap_or_else(|| {
+            let largest = pools.iter().map(|p| p.pool_wo_sz).max().unwrap_or(1);
+            largest.div_ceil(width * 32)
+        });
+        print!("{}", render_ansi(&pools, width, words_per_cell));
+    }
+}
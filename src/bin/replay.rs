@@ -0,0 +1,51 @@
+// Replays an allocation trace(written with the trace feature) against the next fit allocator and
+// against the system allocator, and prints how each of them did.
+//
+// replay <trace>
+use std::process::exit;
+
+use rust_allocator::{
+    replay::{replay, ReplayReport, ReplayTarget},
+    trace::read_trace,
+};
+
+fn print_report(report: &ReplayReport) {
+    let or_na = |v: Option<String>| v.unwrap_or_else(|| "n/a".to_string());
+    println!("{:?}", report.target);
+    println!("  time:                   {:?}", report.elapsed);
+    println!(
+        "  allocs/deallocs:        {}/{}",
+        report.allocs, report.deallocs
+    );
+    println!("  unknown deallocs:       {}", report.unknown_deallocs);
+    println!("  skipped sweeps:         {}", report.skipped_sweeps);
+    println!("  peak live words:        {}", report.peak_live_words);
+    println!(
+        "  peak heap words:        {}",
+        or_na(report.peak_heap_words.map(|w| w.to_string()))
+    );
+    println!(
+        "  external fragmentation: {}",
+        or_na(report.external_fragmentation.map(|f| format!("{f:.4}")))
+    );
+}
+
+fn main() {
+    let Some(path) = std::env::args().nth(1) else {
+        eprintln!("usage: replay <trace>");
+        exit(2)
+    };
+
+    let records = match std::fs::File::open(&path).and_then(|mut f| read_trace(&mut f)) {
+        Ok(records) => records,
+        Err(e) => {
+            eprintln!("replay: {path}: {e}");
+            exit(1)
+        }
+    };
+    println!("{path}: {} records", records.len());
+
+    for target in [ReplayTarget::NextFit, ReplayTarget::System] {
+        print_report(&replay(&records, target));
+    }
+}
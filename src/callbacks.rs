@@ -0,0 +1,27 @@
+// Hooks that embedders can register on the global allocator through the C ABI. A callback must
+// not call back into the allocator(alloc, dealloc, sweep), the heap is in the middle of being
+// changed when some of them run.
+pub type AllocCallback = extern "C" fn(ptr: *mut u8, wo_sz: usize);
+pub type FreeCallback = extern "C" fn(ptr: *mut u8, wo_sz: usize);
+// `pool` is the start of the memory that was added to the heap, `pool_wo_sz` its size in words
+pub type ExpandCallback = extern "C" fn(pool: *mut u8, pool_wo_sz: usize);
+// `reclaimed` is the number of words the sweep gave back to the free list
+pub type SweepDoneCallback = extern "C" fn(reclaimed: usize);
+
+pub struct Callbacks {
+    pub on_alloc: Option<AllocCallback>,
+    pub on_free: Option<FreeCallback>,
+    pub on_expand: Option<ExpandCallback>,
+    pub on_sweep_done: Option<SweepDoneCallback>,
+}
+
+static mut CALLBACKS: Callbacks = Callbacks {
+    on_alloc: None,
+    on_free: None,
+    on_expand: None,
+    on_sweep_done: None,
+};
+
+pub fn get_callbacks() -> &'static mut Callbacks {
+    unsafe { &mut *std::ptr::addr_of_mut!(CALLBACKS) }
+}
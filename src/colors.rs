@@ -9,3 +9,23 @@ pub const CAML_WHITE: Color = 0usize << 8;
 pub const CAML_GRAY: Color = 1usize << 8;
 pub const CAML_BLUE: Color = 2usize << 8;
 pub const CAML_BLACK: Color = 3usize << 8;
+
+pub fn color_name(color: Color) -> &'static str {
+    match color {
+        CAML_BLUE => "Blue",
+        CAML_GRAY => "Gray",
+        CAML_BLACK => "Black",
+        CAML_WHITE => "White",
+        _ => "Unknown",
+    }
+}
+
+pub fn color_from_name(name: &str) -> Option<Color> {
+    match name {
+        "Blue" => Some(CAML_BLUE),
+        "Gray" => Some(CAML_GRAY),
+        "Black" => Some(CAML_BLACK),
+        "White" => Some(CAML_WHITE),
+        _ => None,
+    }
+}
@@ -15,18 +15,40 @@ use crate::{
 };
 
 use super::{
-    globals::{NfGlobals, SentinelType},
-    pool::{PoolIter, PoolIterVal},
+    globals::{NfCounters, NfGlobals, SentinelType},
+    pool::PoolIter,
 };
 
+// Where an incremental sweep stopped, see nf_sweep_slice. last_free_block and last_empty_val are
+// what nf_merge needs to know about the blocks right before `hp`
+#[derive(Debug, Clone, Copy)]
+struct SweepCursor {
+    pool: *mut Pool,
+    hp: *mut Header,
+    last_free_block: Value,
+    last_empty_val: Value,
+    // fl_additions when the last slice stopped
+    fl_additions: usize,
+    // By the slices before this one
+    reclaimed: Wsize,
+}
+
 pub struct NfAllocator {
     globals: NfGlobals,
     #[cfg(debug_assertions)]
     last_expandheap_start_end: (usize, usize),
     num_of_heap_expansions: usize,
+    // Pools in the ring, the ones compaction gave back aren't counted
+    pub(super) num_pools: usize,
+    top_heap_wsz: Wsize,
+    sweep_cursor: Option<SweepCursor>,
+    lazy_sweep: bool,
+    // Set when nf_allocate finishes a sweep, to what that sweep reclaimed
+    lazy_sweep_done: Option<Wsize>,
 }
 
 impl NfAllocator {
+    #[cfg_attr(not(test), allow(dead_code))]
     pub fn new() -> Self {
         let sentinel = Box::leak(Box::new(SentinelType {
             filler1: Value(0),
@@ -53,6 +75,7 @@ impl NfAllocator {
         Self {
             globals: NfGlobals {
                 cur_wsz: Wsize::new(0),
+                counters: NfCounters::new(),
                 nf_head: sentinel_head,
                 nf_prev: sentinel_head,
                 nf_last: sentinel_head,
@@ -61,10 +84,15 @@ impl NfAllocator {
             #[cfg(debug_assertions)]
             last_expandheap_start_end: (0usize, 0usize),
             num_of_heap_expansions: 0usize,
+            num_pools: 0usize,
+            top_heap_wsz: Wsize::new(0),
+            sweep_cursor: None,
+            lazy_sweep: false,
+            lazy_sweep_done: None,
         }
     }
 
-    pub fn get_pool_iter(&self) -> PoolIter {
+    pub fn get_pool_iter(&self) -> PoolIter<'_> {
         // at all times pool_head will point to valid pool(the global one with static lifetime or
         // the one gotten through Box::leak)
         PoolIter::new(&self.get_globals().pool_head)
@@ -93,6 +121,26 @@ impl NfAllocator {
         self.num_of_heap_expansions
     }
 
+    #[inline(always)]
+    pub fn get_num_pools(&self) -> usize {
+        self.num_pools
+    }
+
+    #[inline(always)]
+    pub fn get_top_heap_wsz(&self) -> Wsize {
+        self.top_heap_wsz
+    }
+
+    pub fn is_lazy_sweep(&self) -> bool {
+        self.lazy_sweep
+    }
+
+    // Total size of all the pools
+    pub fn get_heap_wsz(&self) -> Wsize {
+        self.get_pool_iter()
+            .fold(Wsize::new(0), |acc, it| acc + it.get_pool().pool_wo_sz)
+    }
+
     #[cfg(feature = "check_invariants")]
     fn check_nf_allocate_block_invariant(&mut self, prev: Value, cur: Value, wh_sz: Wsize) {
         assert!(
@@ -105,7 +153,7 @@ impl NfAllocator {
         );
     }
 
-    fn nf_allocate_block(&mut self, prev: Value, cur: Value, wh_sz: Wsize) -> *mut Header {
+    fn nf_allocate_block(&mut self, prev: Value, cur: Value, wh_sz: Wsize, tag: u8) -> *mut Header {
         let hd_sz = cur.get_header().get_wosize();
 
         #[cfg(feature = "check_invariants")]
@@ -168,7 +216,7 @@ impl NfAllocator {
 
         // Set the header for the memory that we'll be returning, IMP: Make it have CAML_BLACK color
         let val = field_val(cur, offset + 1);
-        *val.get_header() = Header::new(*wosize_whsize(wh_sz).get_val(), CAML_BLACK, 0);
+        *val.get_header() = Header::new(*wosize_whsize(wh_sz).get_val(), CAML_BLACK, tag);
 
         self.get_globals_mut().nf_prev = prev;
 
@@ -176,12 +224,33 @@ impl NfAllocator {
     }
 
     pub fn nf_allocate(&mut self, wo_sz: Wsize) -> *mut Header {
+        self.nf_allocate_tagged(wo_sz, DEFAULT_TAG)
+    }
+
+    pub fn nf_allocate_tagged(&mut self, wo_sz: Wsize, tag: u8) -> *mut Header {
         assert!(*wo_sz.get_val() >= 1);
-        let it = FreeList::new(self.get_globals_mut()).find_next(wo_sz);
+        let mut it = FreeList::new(self.get_globals_mut()).find_next(wo_sz);
+        // With lazy sweeping, the blocks left CAML_WHITE by the last mark are only reclaimed once
+        // the free list can't satisfy a request, one pool at a time
+        while it.is_none() && self.lazy_sweep && self.is_sweeping() {
+            if let Some(reclaimed) = self.nf_sweep_pool() {
+                self.lazy_sweep_done = Some(reclaimed);
+            }
+            it = FreeList::new(self.get_globals_mut()).find_next(wo_sz);
+        }
         match it {
             None => VAL_NULL.0 as *mut Header,
             Some(it) => {
-                self.nf_allocate_block(it.get_actual_prev(), it.get_cur(), whsize_wosize(wo_sz))
+                let counters = &mut self.get_globals_mut().counters;
+                counters.num_allocs += 1;
+                counters.allocated_wsz += whsize_wosize(wo_sz);
+
+                self.nf_allocate_block(
+                    it.get_actual_prev(),
+                    it.get_cur(),
+                    whsize_wosize(wo_sz),
+                    tag,
+                )
             }
         }
     }
@@ -217,7 +286,8 @@ impl NfAllocator {
         Value(std::ptr::addr_of_mut!(pool.first_field) as usize)
     }
 
-    pub fn nf_expand_heap(&mut self, request_wo_sz: Wsize) {
+    // Returns the newly added pool
+    pub fn nf_expand_heap(&mut self, request_wo_sz: Wsize) -> *mut Pool {
         let (layout, _) = utils::get_layout_and_actual_expansion_size(request_wo_sz);
 
         let memory = Self::allocate_for_heap_expansion(&layout);
@@ -232,16 +302,26 @@ impl NfAllocator {
         }
 
         self.num_of_heap_expansions += 1;
+        self.num_pools += 1;
 
         // self.nf_add_block(field_val(mem_hd_val, 1));
-        self.nf_add_pool(pool_val!(memory));
+        let pool = pool_val!(memory);
+        let pool_ptr = std::ptr::addr_of_mut!(*pool);
+        self.nf_add_pool(pool);
+        // Compaction gives pools back, so the heap can be smaller than it once was
+        let heap_wsz = self.get_heap_wsz();
+        if heap_wsz > self.top_heap_wsz {
+            self.top_heap_wsz = heap_wsz;
+        }
 
         #[cfg(feature = "check_invariants")]
         self.check_pool_list_invariant();
 
-        self.nf_add_block(memory)
+        self.nf_add_block(memory);
+        pool_ptr
     }
 
+    #[cfg(any(test, feature = "check_invariants"))]
     pub fn check_pool_list_invariant(&mut self) {
         let head_next_raw = Pool::get_next_raw_from_raw(&self.get_globals().pool_head);
         let head_prev_raw = Pool::get_prev_raw_from_raw(&self.get_globals().pool_head);
@@ -291,25 +371,26 @@ impl NfAllocator {
         //  2) If there's some other pools apart from pool_head
         //      2.i) B1 will handle the case correctly
         //      2.ii) B2 will handle the cases of inserting to somewhere in between and at the end
-        //        as well
+        //        as well, the last pool's next being pool_head whatever its address is
         //      2.iii) B3 should never hit in this particular case
         //
         //
 
-        let head_next_raw = Pool::get_next_raw_from_raw(&self.get_globals().pool_head);
+        let pool_head = self.get_globals().pool_head;
+        let head_next_raw = Pool::get_next_raw_from_raw(&pool_head);
 
         if head_next_raw > this_pool_addr {
             // B1
             //
             // Must go right after head then. pool_head never changes
-            Pool::insert_right_after_left(self.get_globals().pool_head, this_pool_addr);
+            Pool::insert_right_after_left(pool_head, this_pool_addr);
             return;
         }
 
-        if let Some(mut it) = self
-            .get_pool_iter()
-            .find(|x| this_pool_addr < x.get_pool().get_next_raw())
-        {
+        if let Some(mut it) = self.get_pool_iter().find(|x| {
+            let next = x.get_pool().get_next_raw();
+            next == pool_head || this_pool_addr < next
+        }) {
             //B2
             Pool::insert_right_after_left(
                 std::ptr::addr_of_mut!(*it.get_pool_mut()),
@@ -317,7 +398,7 @@ impl NfAllocator {
             );
         } else {
             //B3
-            Pool::insert_right_after_left(self.get_globals().pool_head, this_pool_addr);
+            Pool::insert_right_after_left(pool_head, this_pool_addr);
         }
     }
 
@@ -361,6 +442,25 @@ impl NfAllocator {
         );
     }
 
+    // Follows the next pointers from nf_head without going through NfIter, so none of the globals
+    // are touched. The walk is bounded by the number of words that the pools hold, so a cycle in
+    // the free list can't make it loop forever. The next pointers aren't read with get_next, as
+    // its check_invariants assert would go off on a free list heap_verify is meant to report on.
+    pub(super) fn collect_free_list(&self) -> Vec<Value> {
+        let max_len: usize = self
+            .get_pool_iter()
+            .map(|it| *it.get_pool().pool_wo_sz.get_val())
+            .sum();
+
+        let mut fl = vec![];
+        let mut cur = *utils::field_ref_mut(&self.get_globals().nf_head, 0);
+        while cur != VAL_NULL && fl.len() <= max_len {
+            fl.push(cur);
+            cur = *utils::field_ref_mut(&cur, 0);
+        }
+        fl
+    }
+
     #[cfg(not(feature = "no_merge"))]
     fn merge_and_update_global(&mut self, left: Value, right: Value) {
         let merged = utils::try_merge(left, right);
@@ -374,8 +474,24 @@ impl NfAllocator {
         }
     }
 
+    // A sweep may leave nf_last NULL, or behind the actual last entry when it adds blocks at the
+    // end of the free list. nf_iter fixes it up whenever it gets to the end, but nf_deallocate
+    // uses it without going through the free list
+    fn fix_nf_last(&mut self) {
+        let mut last = self.get_globals().nf_last;
+        if last == VAL_NULL {
+            last = self.get_globals().nf_head;
+        }
+        while *get_next(&last) != VAL_NULL {
+            last = *get_next(&last);
+        }
+        self.get_globals_mut().nf_last = last;
+    }
+
     pub fn nf_deallocate(&mut self, val: Value) {
+        self.fix_nf_last();
         self.get_globals_mut().cur_wsz += whsize_wosize(val.get_header().get_wosize());
+        self.get_globals_mut().counters.num_frees += 1;
 
         *val.get_header() = Header::new(
             *val.get_header().get_wosize().get_val(),
@@ -434,56 +550,166 @@ impl NfAllocator {
         }
     }
 
-    pub fn nf_sweep(&mut self) {
-        let all_pools = self.get_pool_iter().collect::<Vec<PoolIterVal>>();
-        let mut work_done = vec![];
-
-        for mut it in all_pools {
-            work_done.push(self.sweep(it.get_pool_mut()));
+    // Starts a new sweep, unless there's one in progress already. Blocks are only actually swept
+    // by nf_sweep_slice
+    pub fn nf_start_sweep(&mut self) {
+        if self.sweep_cursor.is_some() {
+            return;
         }
+        let pool = Pool::get_next_raw_from_raw(&self.get_globals().pool_head);
+        self.sweep_cursor = Some(SweepCursor {
+            pool,
+            hp: unsafe { std::ptr::addr_of_mut!((*pool).hd) },
+            last_free_block: self.get_globals().nf_head,
+            last_empty_val: Value(0),
+            fl_additions: self.fl_additions(),
+            reclaimed: Wsize::new(0),
+        });
     }
 
-    fn sweep(&mut self, pool: &mut Pool) -> Wsize {
-        let mut cur_hp = std::ptr::addr_of_mut!(pool.hd);
-        let limit = std::ptr::addr_of!(*pool) as usize + pool.pool_wo_sz.to_bytesize();
+    pub fn is_sweeping(&self) -> bool {
+        self.sweep_cursor.is_some()
+    }
 
-        let mut sweeped_wsz = Wsize::new(0);
+    pub fn set_lazy_sweep(&mut self, lazy: bool) {
+        self.lazy_sweep = lazy;
+    }
+
+    // What the sweep nf_allocate finished reclaimed, if it finished one since the last call
+    pub fn take_lazy_sweep_done(&mut self) -> Option<Wsize> {
+        self.lazy_sweep_done.take()
+    }
 
-        let mut last_free_block = self.get_globals().nf_head;
-        let mut last_empty_val = Value(0);
+    // Finishes the sweep in progress, or does a whole new one. Returns the number of words given
+    // back to the free list
+    pub fn nf_sweep(&mut self) -> Wsize {
+        self.nf_start_sweep();
+        self.nf_sweep_slice(Wsize::new(usize::MAX))
+            .unwrap_or(Wsize::new(0))
+    }
 
-        //
-        while (cur_hp as usize) < limit {
-            let cur_hd = hd_hp!(cur_hp);
-            let cur_val = val_hp!(cur_hp);
-            match cur_hd.get_color() {
-                CAML_BLACK => {
-                    // Live
-                    *cur_hd = Header::new(
-                        *cur_hd.get_wosize().get_val(),
-                        CAML_WHITE, // Black -> White
-                        cur_hd.get_tag(),
-                    );
-                }
-                CAML_WHITE => {
-                    // Dead
-
-                    // last_free_block is always something in which get_next is valid
-                    // If the first block we encounter itself is CAML_WHITE, the get_next call in
-                    // B1 branch in nf_merge is valid
-                    sweeped_wsz += whsize_wosize(cur_val.get_header().get_wosize());
-                    self.nf_merge(cur_val, &mut last_empty_val, &mut last_free_block);
-                }
-                CAML_BLUE => {
-                    // In free list
-                    last_free_block = val_hp!(cur_hp);
+    // Sweeps blocks until `budget` words worth of them have been looked at and remembers where it
+    // stopped for the next call. Once the last pool is done, returns the number of words the whole
+    // sweep gave back to the free list. Returns None while there's still some left to sweep, and
+    // when there's no sweep in progress
+    pub fn nf_sweep_slice(&mut self, budget: Wsize) -> Option<Wsize> {
+        self.sweep_slice(budget, false)
+    }
+
+    // Sweeps what's left of the pool the sweep in progress stopped in. Same return value as
+    // nf_sweep_slice
+    pub fn nf_sweep_pool(&mut self) -> Option<Wsize> {
+        self.sweep_slice(Wsize::new(usize::MAX), true)
+    }
+
+    fn sweep_slice(&mut self, budget: Wsize, one_pool: bool) -> Option<Wsize> {
+        let mut cursor = self.sweep_cursor.take()?;
+        self.resume_sweep(&mut cursor);
+
+        let pool_head = self.get_globals().pool_head;
+        let mut work_done = 0;
+        let mut swept = Wsize::new(0);
+        while cursor.pool != pool_head {
+            let limit = cursor.pool as usize + Pool::get_wo_sz_from_raw(&cursor.pool).to_bytesize();
+            while (cursor.hp as usize) < limit {
+                if work_done >= *budget.get_val() {
+                    self.pause_sweep(cursor, swept);
+                    return None;
                 }
-                _ => unreachable!("Nothing should have Gray color in sweep phase"),
+                work_done += *whsize_wosize(hd_hp!(cursor.hp).get_wosize()).get_val();
+                swept += self.sweep_block(&mut cursor);
+            }
+            cursor.pool = Pool::get_next_raw_from_raw(&cursor.pool);
+            cursor.hp = unsafe { std::ptr::addr_of_mut!((*cursor.pool).hd) };
+            if one_pool && cursor.pool != pool_head {
+                self.pause_sweep(cursor, swept);
+                return None;
+            }
+        }
+
+        let counters = &mut self.get_globals_mut().counters;
+        counters.num_sweeps += 1;
+        counters.swept_wsz += swept;
+        Some(cursor.reclaimed + swept)
+    }
+
+    fn pause_sweep(&mut self, mut cursor: SweepCursor, swept: Wsize) {
+        cursor.fl_additions = self.fl_additions();
+        cursor.reclaimed += swept;
+        self.sweep_cursor = Some(cursor);
+        self.get_globals_mut().counters.swept_wsz += swept;
+    }
+
+    // Frees can only be counted as adding to the free list, but that's only an issue for
+    // resume_sweep's fast path
+    fn fl_additions(&self) -> usize {
+        self.get_globals().counters.num_frees + self.num_of_heap_expansions
+    }
+
+    // Between two slices the mutator may have allocated last_free_block, added free blocks between
+    // it and the cursor, or freed the block right before the cursor, which then swallowed the
+    // block at the cursor. In all those cases the free list entry right before the cursor is looked
+    // up again, and the cursor is moved past it if it ended up inside of it.
+    fn resume_sweep(&mut self, cursor: &mut SweepCursor) {
+        let nf_head = self.get_globals().nf_head;
+        if cursor.fl_additions == self.fl_additions()
+            && (cursor.last_free_block == nf_head
+                || cursor.last_free_block.get_header().get_color() == CAML_BLUE)
+        {
+            return;
+        }
+
+        let cur_val = val_hp!(cursor.hp);
+        let mut prev = nf_head;
+        while *get_next(&prev) != VAL_NULL && *get_next(&prev) < cur_val {
+            prev = *get_next(&prev);
+        }
+        cursor.last_free_block = prev;
+
+        if prev != nf_head && prev.get_next_from_size() > cur_val {
+            cursor.hp = hp_val!(prev.get_next_from_size());
+            cursor.last_empty_val = Value(0);
+        }
+    }
+
+    // Sweeps the block at the cursor and moves the cursor to the next one. Returns the number of
+    // words given back to the free list
+    fn sweep_block(&mut self, cursor: &mut SweepCursor) -> Wsize {
+        let cur_hd = hd_hp!(cursor.hp);
+        let cur_val = val_hp!(cursor.hp);
+        let mut sweeped_wsz = Wsize::new(0);
+        match cur_hd.get_color() {
+            CAML_BLACK => {
+                // Live
+                *cur_hd = Header::new(
+                    *cur_hd.get_wosize().get_val(),
+                    CAML_WHITE, // Black -> White
+                    cur_hd.get_tag(),
+                );
             }
-            cur_hp = hp_val!(cur_val.get_next_from_size());
+            CAML_WHITE => {
+                // Dead
+
+                // last_free_block is always something in which get_next is valid
+                // If the first block we encounter itself is CAML_WHITE, the get_next call in
+                // B1 branch in nf_merge is valid
+                sweeped_wsz += whsize_wosize(cur_val.get_header().get_wosize());
+                self.nf_merge(
+                    cur_val,
+                    &mut cursor.last_empty_val,
+                    &mut cursor.last_free_block,
+                );
+            }
+            CAML_BLUE => {
+                // In free list
+                cursor.last_free_block = cur_val;
+            }
+            _ => unreachable!("Nothing should have Gray color in sweep phase"),
         }
+        cursor.hp = hp_val!(cur_val.get_next_from_size());
         sweeped_wsz
     }
+
     fn nf_merge(
         &mut self,
         mut cur_val: Value,
@@ -558,6 +784,7 @@ impl NfAllocator {
 static mut GLOBAL_ALLOC: NfAllocator = NfAllocator {
     globals: NfGlobals {
         cur_wsz: Wsize::new(0),
+        counters: NfCounters::new(),
         nf_head: Value(0),
         nf_prev: Value(0),
         nf_last: Value(0),
@@ -566,17 +793,23 @@ static mut GLOBAL_ALLOC: NfAllocator = NfAllocator {
     #[cfg(debug_assertions)]
     last_expandheap_start_end: (0usize, 0usize),
     num_of_heap_expansions: 0usize,
+    num_pools: 0usize,
+    top_heap_wsz: Wsize::new(0),
+    sweep_cursor: None,
+    lazy_sweep: false,
+    lazy_sweep_done: None,
 };
 
 pub fn get_global_allocator() -> &'static mut NfAllocator {
     static ONCE: std::sync::Once = std::sync::Once::new();
     ONCE.call_once(|| unsafe {
         GLOBAL_ALLOC.globals.cur_wsz = NfGlobals::get().cur_wsz;
+        GLOBAL_ALLOC.globals.counters = NfGlobals::get().counters;
         GLOBAL_ALLOC.globals.nf_head = NfGlobals::get().nf_head;
         GLOBAL_ALLOC.globals.nf_prev = NfGlobals::get().nf_prev;
         GLOBAL_ALLOC.globals.nf_last = NfGlobals::get().nf_last;
         GLOBAL_ALLOC.globals.pool_head = NfGlobals::get().pool_head;
     });
 
-    unsafe { &mut GLOBAL_ALLOC }
+    unsafe { &mut *std::ptr::addr_of_mut!(GLOBAL_ALLOC) }
 }
@@ -0,0 +1,167 @@
+use crate::{
+    colors::{CAML_BLUE, CAML_WHITE},
+    header::Header,
+    hp_val,
+    utils::{get_next, get_pool_layout, whsize_wosize},
+    val_hp,
+    value::{Value, VAL_NULL},
+    word::Wsize,
+    DEFAULT_TAG,
+};
+
+use super::{allocator::NfAllocator, pool::Pool};
+
+// Where nf_compact moves every allocated block
+#[derive(Debug, Default)]
+pub struct Forwarding {
+    // Sorted, new[i] is where old[i] goes
+    old: Vec<usize>,
+    new: Vec<usize>,
+    pub pools_freed: usize,
+}
+
+impl Forwarding {
+    // Addresses of the allocated blocks before they're moved, sorted
+    pub fn old_blocks(&self) -> &[usize] {
+        &self.old
+    }
+
+    // New address of the block at `old`. None if `old` isn't an allocated block
+    pub fn forward(&self, old: Value) -> Option<Value> {
+        self.old
+            .binary_search(&old.0)
+            .ok()
+            .map(|i| Value(self.new[i]))
+    }
+
+    // (old, new) of the blocks that don't stay where they are
+    pub fn moved(&self) -> impl Iterator<Item = (Value, Value)> + '_ {
+        self.old
+            .iter()
+            .zip(&self.new)
+            .filter(|(o, n)| o != n)
+            .map(|(o, n)| (Value(*o), Value(*n)))
+    }
+
+    pub fn num_moved(&self) -> usize {
+        self.moved().count()
+    }
+}
+
+fn first_hp(pool: *mut Pool) -> usize {
+    unsafe { std::ptr::addr_of!((*pool).hd) as usize }
+}
+
+fn pool_limit(pool: *mut Pool) -> usize {
+    pool as usize + Pool::get_wo_sz_from_raw(&pool).to_bytesize()
+}
+
+impl NfAllocator {
+    // Slides every allocated block towards the start of the heap, filling the lowest pools first,
+    // like OCaml's compactor. The allocator doesn't know where the pointers to the blocks are, so
+    // `update_pointers` is called with where each block is going, before anything is moved, and
+    // has to rewrite all of them. Afterwards each pool has at most one free block at its end, and
+    // the pools left without any allocated block are given back, except for the first one.
+    pub fn nf_compact(&mut self, update_pointers: impl FnOnce(&Forwarding)) -> Forwarding {
+        assert!(!self.is_sweeping(), "Compacting in the middle of a sweep");
+
+        let pools = self
+            .get_pool_iter()
+            .map(|mut it| std::ptr::addr_of_mut!(*it.get_pool_mut()))
+            .collect::<Vec<*mut Pool>>();
+        let mut forwarding = Forwarding::default();
+        if pools.is_empty() {
+            return forwarding;
+        }
+
+        // Where the free space of each pool will start, None for the pools that end up empty
+        let mut pool_ends = vec![None; pools.len()];
+        let mut dst = 0;
+        let mut dst_hp = first_hp(pools[0]);
+        for pool in pools.iter() {
+            for val in unsafe { &**pool }.blocks() {
+                let hd = val.get_header();
+                if hd.get_color() == CAML_BLUE || *hd.get_wosize().get_val() == 0 {
+                    continue;
+                }
+                let bytes = whsize_wosize(hd.get_wosize()).to_bytesize();
+                // Never goes past the pool `val` is in, it fits there at worst
+                while dst_hp + bytes > pool_limit(pools[dst]) {
+                    pool_ends[dst] = Some(dst_hp);
+                    dst += 1;
+                    dst_hp = first_hp(pools[dst]);
+                }
+                forwarding.old.push(val.0);
+                forwarding.new.push(val_hp!(dst_hp as *mut Header).0);
+                dst_hp += bytes;
+            }
+        }
+        pool_ends[dst] = Some(dst_hp);
+
+        update_pointers(&forwarding);
+
+        // Destinations are in the same order as the blocks, and never after them, so a block is
+        // never overwritten before it's moved
+        for (old, new) in forwarding.old.iter().zip(&forwarding.new) {
+            let old = Value(*old);
+            let wh_sz = whsize_wosize(old.get_header().get_wosize());
+            unsafe {
+                std::ptr::copy(
+                    hp_val!(old) as *const usize,
+                    hp_val!(Value(*new)) as *mut usize,
+                    *wh_sz.get_val(),
+                );
+            }
+        }
+
+        self.rebuild_after_compaction(&pools, &pool_ends, &mut forwarding);
+        forwarding
+    }
+
+    fn rebuild_after_compaction(
+        &mut self,
+        pools: &[*mut Pool],
+        pool_ends: &[Option<usize>],
+        forwarding: &mut Forwarding,
+    ) {
+        let nf_head = self.get_globals().nf_head;
+        let mut last = nf_head;
+        let mut cur_wsz = Wsize::new(0);
+
+        for (pool, end) in pools.iter().zip(pool_ends) {
+            let Some(end) = *end else {
+                Pool::unlink(*pool);
+                unsafe {
+                    std::alloc::dealloc(
+                        *pool as *mut u8,
+                        get_pool_layout(Pool::get_wo_sz_from_raw(pool)),
+                    )
+                };
+                self.num_pools -= 1;
+                forwarding.pools_freed += 1;
+                continue;
+            };
+
+            let free_wsz = Wsize::from_bytesize(pool_limit(*pool) - end);
+            let free_val = val_hp!(end as *mut Header);
+            match *free_wsz.get_val() {
+                0 => {}
+                // Not enough room for the next pointer, it's left as a fragment like the ones
+                // nf_allocate_block leaves behind
+                1 => *free_val.get_header() = Header::new(0, CAML_WHITE, DEFAULT_TAG),
+                wh_sz => {
+                    *free_val.get_header() = Header::new(wh_sz - 1, CAML_BLUE, DEFAULT_TAG);
+                    *get_next(&last) = free_val;
+                    last = free_val;
+                    cur_wsz += free_wsz;
+                }
+            }
+        }
+        *get_next(&last) = VAL_NULL;
+
+        let globals = self.get_globals_mut();
+        globals.cur_wsz = cur_wsz;
+        globals.nf_prev = nf_head;
+        globals.nf_last = last;
+    }
+}
@@ -0,0 +1,47 @@
+use std::io::{self, Write};
+
+use crate::{colors::color_name, value::Value};
+
+use super::{allocator::NfAllocator, pool::Pool};
+
+impl NfAllocator {
+    // Writes the whole heap as JSON Lines. Every pool gets one line, followed by one line for each
+    // block inside of it, in address order.
+    //
+    // {"type":"pool","addr":..,"pool_wo_sz":..}
+    // {"type":"block","addr":..,"wosize":..,"color":"Black","tag":..,"in_free_list":false}
+    //
+    // addr of a block is the address of its first field, same as what alloc returns
+    pub fn heap_dump(&self, out: &mut impl Write) -> io::Result<()> {
+        let mut fl = self.collect_free_list();
+        fl.sort_unstable_by_key(|v| v.0);
+
+        for it in self.get_pool_iter() {
+            let pool = it.get_pool();
+            writeln!(
+                out,
+                r#"{{"type":"pool","addr":{},"pool_wo_sz":{}}}"#,
+                std::ptr::addr_of!(*pool) as usize,
+                pool.pool_wo_sz.get_val()
+            )?;
+            Self::dump_pool(pool, &fl, out)?;
+        }
+        out.flush()
+    }
+
+    fn dump_pool(pool: &Pool, fl: &[Value], out: &mut impl Write) -> io::Result<()> {
+        for cur_val in pool.blocks() {
+            let cur_hd = cur_val.get_header();
+            writeln!(
+                out,
+                r#"{{"type":"block","addr":{},"wosize":{},"color":"{}","tag":{},"in_free_list":{}}}"#,
+                cur_val.0,
+                cur_hd.get_wosize().get_val(),
+                color_name(cur_hd.get_color()),
+                cur_hd.get_tag(),
+                fl.binary_search_by_key(&cur_val.0, |v| v.0).is_ok()
+            )?;
+        }
+        Ok(())
+    }
+}
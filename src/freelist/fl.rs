@@ -1,5 +1,6 @@
+#[cfg(feature = "check_invariants")]
+use crate::colors::CAML_BLUE;
 use crate::{
-    colors::CAML_BLUE,
     utils::get_next,
     value::{Value, VAL_NULL},
     word::Wsize,
@@ -22,13 +23,19 @@ impl FreeList<'_> {
             visited_start_once: false,
         }
     }
-    pub fn new(g: &mut NfGlobals) -> FreeList {
+    pub fn new(g: &mut NfGlobals) -> FreeList<'_> {
         FreeList { globals: g }
     }
 
+    // The search nf_allocate does, the only walk of the free list counted in fl_visited
     pub fn find_next(&mut self, wo_sz: Wsize) -> Option<NfIterVal> {
-        self.nf_iter()
-            .find(|e| e.get_cur().get_header().get_wosize().get_val() >= wo_sz.get_val())
+        let mut visited = 0;
+        let found = self.nf_iter().find(|e| {
+            visited += 1;
+            e.get_cur().get_header().get_wosize().get_val() >= wo_sz.get_val()
+        });
+        self.globals.counters.fl_visited += visited;
+        found
     }
 }
 
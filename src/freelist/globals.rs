@@ -24,11 +24,41 @@ static mut SENTINEL: SentinelType = SentinelType {
     filler2: Value(0),
 };
 
+// Cumulative counters, these only ever go up. They're bumped on the hot paths, so reading them
+// is cheap compared to walking the heap.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct NfCounters {
+    // words handed out by nf_allocate, headers included
+    pub(super) allocated_wsz: Wsize,
+    pub(super) num_allocs: usize,
+    pub(super) num_frees: usize,
+    // words given back to the free list by nf_sweep
+    pub(super) swept_wsz: Wsize,
+    pub(super) num_sweeps: usize,
+    // free list nodes nf_allocate has gone over while looking for a block
+    pub(super) fl_visited: usize,
+}
+
+impl NfCounters {
+    pub const fn new() -> Self {
+        NfCounters {
+            allocated_wsz: Wsize::new(0),
+            num_allocs: 0,
+            num_frees: 0,
+            swept_wsz: Wsize::new(0),
+            num_sweeps: 0,
+            fl_visited: 0,
+        }
+    }
+}
+
 #[derive(Debug)]
 #[repr(C)]
 pub struct NfGlobals {
     // this will always have the total words in freelist
     pub(super) cur_wsz: Wsize,
+    pub(super) counters: NfCounters,
     // this always points to one value throughout the
     // program(SENTINEL.first_field specifically)
     pub(super) nf_head: Value,
@@ -64,6 +94,7 @@ impl NfGlobals {
         };
         static mut NF_GLOBAL: NfGlobals = NfGlobals {
             cur_wsz: Wsize::new(0),
+            counters: NfCounters::new(),
             nf_head: Value(0),
             nf_prev: Value(0),
             nf_last: Value(0),
@@ -84,6 +115,6 @@ impl NfGlobals {
             };
         });
 
-        unsafe { &mut NF_GLOBAL }
+        unsafe { &mut *std::ptr::addr_of_mut!(NF_GLOBAL) }
     }
 }
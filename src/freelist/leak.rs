@@ -0,0 +1,102 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display},
+};
+
+use crate::{
+    colors::{CAML_BLACK, CAML_WHITE},
+    utils::whsize_wosize,
+};
+
+use super::allocator::NfAllocator;
+
+// Blocks still allocated that have the same wosize and tag
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeakGroup {
+    pub wosize: usize,
+    pub tag: u8,
+    pub count: usize,
+    // headers included
+    pub words: usize,
+    // Addresses of the blocks, as returned by alloc
+    pub addrs: Vec<usize>,
+    // Sequence number in the trace of the alloc that returned each of addrs. Only filled in with
+    // the trace feature, by rust_allocator::leak_report
+    pub alloc_seqs: Vec<Option<u64>>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LeakReport {
+    // Sorted by words, biggest first
+    pub groups: Vec<LeakGroup>,
+    pub blocks: usize,
+    pub words: usize,
+}
+
+impl NfAllocator {
+    // Every CAML_BLACK/CAML_WHITE block that isn't a zero sized fragment, which is everything
+    // alloc has handed out and nobody has given back(either through dealloc or sweep)
+    pub fn leak_report(&self) -> LeakReport {
+        let mut groups: BTreeMap<(usize, u8), LeakGroup> = BTreeMap::new();
+
+        for it in self.heap_walker() {
+            let hd = it.get_header();
+            let wo_sz = *hd.get_wosize().get_val();
+            if !matches!(hd.get_color(), CAML_BLACK | CAML_WHITE) || wo_sz == 0 {
+                continue;
+            }
+
+            let group = groups
+                .entry((wo_sz, hd.get_tag()))
+                .or_insert_with(|| LeakGroup {
+                    wosize: wo_sz,
+                    tag: hd.get_tag(),
+                    count: 0,
+                    words: 0,
+                    addrs: vec![],
+                    alloc_seqs: vec![],
+                });
+            group.count += 1;
+            group.words += *whsize_wosize(hd.get_wosize()).get_val();
+            group.addrs.push(it.get_addr());
+        }
+
+        let mut groups = groups.into_values().collect::<Vec<LeakGroup>>();
+        groups.sort_by_key(|g| std::cmp::Reverse(g.words));
+
+        LeakReport {
+            blocks: groups.iter().map(|g| g.count).sum(),
+            words: groups.iter().map(|g| g.words).sum(),
+            groups,
+        }
+    }
+}
+
+impl Display for LeakReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "leak report: {} blocks, {} words still allocated",
+            self.blocks, self.words
+        )?;
+        for group in &self.groups {
+            write!(
+                f,
+                "  wosize {:>8} tag {:>3}: {:>8} blocks {:>10} words",
+                group.wosize, group.tag, group.count, group.words
+            )?;
+            let seqs = group.alloc_seqs.iter().flatten().collect::<Vec<_>>();
+            if !seqs.is_empty() {
+                write!(f, ", alloc #")?;
+                for (i, seq) in seqs.iter().take(8).enumerate() {
+                    write!(f, "{}{}", if i == 0 { "" } else { ", " }, seq)?;
+                }
+                if seqs.len() > 8 {
+                    write!(f, ", ...")?;
+                }
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
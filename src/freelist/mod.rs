@@ -1,12 +1,18 @@
 pub mod allocator;
+pub mod compact;
+pub mod dump;
 pub mod fl;
 mod globals;
+pub mod leak;
 pub mod pool;
+pub mod stats;
+pub mod verify;
+pub mod walker;
 
 #[cfg(test)]
 mod tests {
     use crate::{
-        colors::{CAML_BLACK, CAML_BLUE, CAML_WHITE},
+        colors::{CAML_BLACK, CAML_BLUE, CAML_GRAY, CAML_WHITE},
         freelist::pool::Pool,
         header::Header,
         pool_val,
@@ -35,7 +41,8 @@ mod tests {
             Wsize::from_bytesize(layout.size())
         );
 
-        unsafe { std::alloc::dealloc(pool_val!(memory) as *mut Pool as *mut u8, layout) };
+        let pool = pool_val!(memory) as *mut Pool as *mut u8;
+        unsafe { std::alloc::dealloc(pool, layout) };
     }
 
     #[test]
@@ -87,7 +94,7 @@ mod tests {
         assert_eq!(allocator.get_globals().cur_wsz, cur_wsz);
 
         let to_be_freed = allocations.get_mut(0).unwrap().take().unwrap();
-        assert!(allocations.get(0).unwrap().is_none());
+        assert!(allocations.first().unwrap().is_none());
 
         let allocatable_memory_left = FreeList::new(allocator.get_globals_mut())
             .nf_iter()
@@ -187,9 +194,9 @@ mod tests {
     #[test]
     fn sweep_test() {
         let mut allocator = NfAllocator::new();
-        let _ = allocator.nf_expand_heap(Wsize::new(10)); // This'll add a new pool,
-                                                          // $MIN_EXPANSION_WORSIZE  words will be
-                                                          // malloc'd
+        allocator.nf_expand_heap(Wsize::new(10)); // This'll add a new pool,
+                                                  // $MIN_EXPANSION_WORSIZE  words will be
+                                                  // malloc'd
 
         let initial_cur_wsz = allocator.get_globals().cur_wsz;
         // Allocation 1
@@ -344,4 +351,394 @@ mod tests {
         assert_eq!(allocator.get_globals().nf_prev, only_val_in_fl);
         assert_eq!(allocator.get_globals().nf_last, only_val_in_fl);
     }
+
+    #[test]
+    fn heap_verify_test() {
+        let mut allocator = NfAllocator::new();
+        assert!(allocator.heap_verify().is_ok());
+
+        allocator.nf_expand_heap(Wsize::new(10));
+        allocator.nf_expand_heap(Wsize::new(10));
+
+        let report = allocator.heap_verify();
+        assert!(report.is_ok(), "{report:?}");
+        assert_eq!(report.pools, 2);
+        assert_eq!(report.free_blocks, 2);
+        assert_eq!(report.free_list_len, 2);
+
+        let vals = [10, 20, 30, 40]
+            .iter()
+            .map(|sz| val_hp!(allocator.nf_allocate(Wsize::new(*sz))))
+            .collect::<Vec<Value>>();
+
+        let report = allocator.heap_verify();
+        assert!(report.is_ok(), "{report:?}");
+        assert_eq!(report.blocks, 2 + vals.len());
+
+        // Blocks are split off from the right, so the pool now looks like
+        // FFFF[40][30][20][10]
+        allocator.nf_deallocate(vals[1]);
+        let report = allocator.heap_verify();
+        assert!(report.is_ok(), "{report:?}");
+        assert_eq!(report.free_blocks, 3);
+        assert_eq!(*allocator.get_globals().cur_wsz.get_val(), report.free_wsz);
+
+        // Corrupting cur_wsz
+        allocator.get_globals_mut().cur_wsz += Wsize::new(1);
+        let report = allocator.heap_verify();
+        assert!(report.cur_wsz_mismatch);
+        assert!(!report.is_ok());
+        allocator.get_globals_mut().cur_wsz -= Wsize::new(1);
+
+        // A block that looks free but was never put in the free list(nor merged with the free
+        // block right before it)
+        let hd = vals[3].get_header().clone();
+        *vals[3].get_header() = Header::new(*hd.get_wosize().get_val(), CAML_BLUE, hd.get_tag());
+        let report = allocator.heap_verify();
+        assert_eq!(report.free_blocks_not_in_fl, 1);
+        assert_eq!(report.unmerged_free_blocks, 1);
+        assert!(!report.is_ok());
+        *vals[3].get_header() = hd;
+
+        // Gray outside of a mark phase
+        let hd = vals[2].get_header().clone();
+        *vals[2].get_header() = Header::new(*hd.get_wosize().get_val(), CAML_GRAY, hd.get_tag());
+        assert_eq!(allocator.heap_verify().bad_colors, 1);
+        *vals[2].get_header() = hd;
+
+        // A free list entry that isn't free anymore is reported, even with check_invariants on
+        let hd = vals[1].get_header().clone();
+        *vals[1].get_header() = Header::new(*hd.get_wosize().get_val(), CAML_BLACK, hd.get_tag());
+        let report = allocator.heap_verify();
+        assert_eq!(report.fl_entries_not_free, 1);
+        assert!(!report.is_ok());
+        *vals[1].get_header() = hd;
+
+        assert!(allocator.heap_verify().is_ok());
+    }
+
+    #[test]
+    fn heap_stats_test() {
+        let mut allocator = NfAllocator::new();
+        assert_eq!(allocator.heap_stats(), Default::default());
+
+        allocator.nf_expand_heap(Wsize::new(10));
+        let pool_wo_sz = allocator.heap_stats().heap_words;
+        let first_block_wosz =
+            *Pool::get_header_size_from_pool_wo_sz(Wsize::new(pool_wo_sz)).get_val();
+
+        let stats = allocator.heap_stats();
+        assert_eq!(stats.pools, 1);
+        assert_eq!(stats.heap_expansions, 1);
+        assert_eq!(stats.top_heap_words, pool_wo_sz);
+        assert_eq!(stats.free_blocks, 1);
+        assert_eq!(stats.largest_free, first_block_wosz);
+        assert_eq!(stats.free_words, first_block_wosz + 1);
+        assert_eq!(stats.live_words, 0);
+
+        let a = val_hp!(allocator.nf_allocate(Wsize::new(10)));
+        let b = val_hp!(allocator.nf_allocate(Wsize::new(20)));
+        let stats = allocator.heap_stats();
+        assert_eq!(stats.live_blocks, 2);
+        assert_eq!(stats.live_words, 11 + 21);
+        assert_eq!(stats.free_words + stats.live_words, first_block_wosz + 1);
+        assert_eq!(stats.largest_free, first_block_wosz - 32);
+
+        // Leaving exactly one word behind makes nf_allocate_block put a zero sized header there
+        let c = val_hp!(allocator.nf_allocate(Wsize::new(stats.largest_free - 1)));
+        let stats = allocator.heap_stats();
+        assert_eq!(stats.free_blocks, 0);
+        assert_eq!(stats.free_words, 0);
+        assert_eq!(stats.fragments, 1);
+        assert_eq!(stats.live_blocks, 3);
+
+        for val in [a, b, c] {
+            allocator.nf_deallocate(val);
+        }
+        allocator.nf_expand_heap(Wsize::new(10));
+        let stats = allocator.heap_stats();
+        assert_eq!(stats.pools, 2);
+        assert_eq!(stats.heap_words, 2 * pool_wo_sz);
+        assert_eq!(stats.top_heap_words, 2 * pool_wo_sz);
+        assert_eq!(stats.live_blocks, 0);
+        // a, b and c are merged into one block unless no_merge is on, the new pool is one more
+        let free_blocks = if cfg!(feature = "no_merge") { 4 } else { 2 };
+        assert_eq!(stats.free_blocks, free_blocks);
+    }
+
+    #[test]
+    fn quick_stat_test() {
+        let mut allocator = NfAllocator::new();
+        assert_eq!(allocator.quick_stat(), Default::default());
+
+        allocator.nf_expand_heap(Wsize::new(10));
+        let vals = [10, 20, 30]
+            .iter()
+            .map(|sz| val_hp!(allocator.nf_allocate(Wsize::new(*sz))))
+            .collect::<Vec<Value>>();
+
+        let stats = allocator.quick_stat();
+        assert_eq!(stats.allocations, 3);
+        assert_eq!(stats.allocated_words, 11 + 21 + 31);
+        assert_eq!(stats.heap_expansions, 1);
+        assert_eq!(stats.free_words, *allocator.get_globals().cur_wsz.get_val());
+        assert!(stats.fl_visited >= 3);
+
+        // Walks that aren't an allocation searching the free list aren't counted
+        allocator.heap_stats();
+        allocator.fragmentation();
+        FreeList::new(allocator.get_globals_mut()).nf_iter().count();
+        assert_eq!(allocator.quick_stat().fl_visited, stats.fl_visited);
+
+        allocator.nf_deallocate(vals[0]);
+        assert_eq!(allocator.quick_stat().frees, 1);
+
+        // vals[1] is unreachable, vals[2] is live
+        let hd = vals[1].get_header().clone();
+        *vals[1].get_header() = Header::new(*hd.get_wosize().get_val(), CAML_WHITE, hd.get_tag());
+        allocator.nf_sweep();
+
+        let stats = allocator.quick_stat();
+        assert_eq!(stats.sweeps, 1);
+        assert_eq!(stats.swept_words, 21);
+        assert_eq!(stats.frees, 1);
+        assert_eq!(stats.allocations, 3);
+    }
+
+    #[test]
+    fn fragmentation_test() {
+        use super::stats::FragmentationReport;
+
+        assert_eq!(FragmentationReport::bucket_of(0), 0);
+        assert_eq!(FragmentationReport::bucket_of(1), 0);
+        assert_eq!(FragmentationReport::bucket_of(2), 1);
+        assert_eq!(FragmentationReport::bucket_of(3), 1);
+        assert_eq!(FragmentationReport::bucket_of(1024), 10);
+        assert_eq!(FragmentationReport::bucket_of(usize::MAX), 63);
+
+        let mut allocator = NfAllocator::new();
+        assert_eq!(allocator.fragmentation(), Default::default());
+
+        allocator.nf_expand_heap(Wsize::new(10));
+        let report = allocator.fragmentation();
+        assert_eq!(report.free_blocks, 1);
+        assert_eq!(report.external_fragmentation, 0.0);
+
+        // FFFF[40][30][20][10], freeing [30] and [10] leaves 3 free blocks
+        let vals = [10, 20, 30, 40]
+            .iter()
+            .map(|sz| val_hp!(allocator.nf_allocate(Wsize::new(*sz))))
+            .collect::<Vec<Value>>();
+        allocator.nf_deallocate(vals[0]);
+        allocator.nf_deallocate(vals[2]);
+
+        let report = allocator.fragmentation();
+        assert_eq!(report.free_blocks, 3);
+        assert_eq!(report.histogram.iter().sum::<usize>(), 3);
+        assert_eq!(report.histogram[FragmentationReport::bucket_of(10)], 1);
+        assert_eq!(report.histogram[FragmentationReport::bucket_of(30)], 1);
+        assert_eq!(report.free_wosize, report.largest_free + 10 + 30,);
+        assert_eq!(
+            report.external_fragmentation,
+            1.0 - report.largest_free as f64 / report.free_wosize as f64
+        );
+        assert!(report.external_fragmentation > 0.0);
+        assert_eq!(report.zero_wosize_headers, 0);
+
+        // The first free block is the only one big enough, leaving one word in it creates a zero
+        // sized header
+        allocator.nf_allocate(Wsize::new(report.largest_free - 1));
+        let report = allocator.fragmentation();
+        assert_eq!(report.free_blocks, 2);
+        assert_eq!(report.zero_wosize_headers, 1);
+    }
+
+    #[test]
+    fn heap_dump_test() {
+        let mut allocator = NfAllocator::new();
+        allocator.nf_expand_heap(Wsize::new(10));
+        let a = val_hp!(allocator.nf_allocate(Wsize::new(10)));
+        let _b = val_hp!(allocator.nf_allocate(Wsize::new(20)));
+        allocator.nf_deallocate(a);
+
+        let mut out = vec![];
+        allocator.heap_dump(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines = out.lines().collect::<Vec<&str>>();
+
+        // pool, FFFF, [20], [10](freed)
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with(r#"{"type":"pool","#));
+        assert!(lines[1].ends_with(r#""color":"Blue","tag":0,"in_free_list":true}"#));
+        assert!(lines[2].contains(r#""wosize":20,"color":"Black""#));
+        assert!(lines[2].ends_with(r#""in_free_list":false}"#));
+        assert_eq!(
+            lines[3],
+            format!(
+                r#"{{"type":"block","addr":{},"wosize":10,"color":"Blue","tag":0,"in_free_list":true}}"#,
+                a.0
+            )
+        );
+    }
+
+    #[test]
+    fn heap_walker_test() {
+        let mut allocator = NfAllocator::new();
+        assert_eq!(allocator.heap_walker().count(), 0);
+
+        allocator.nf_expand_heap(Wsize::new(10));
+        allocator.nf_expand_heap(Wsize::new(10));
+        let vals = [10, 20, 30]
+            .iter()
+            .map(|sz| val_hp!(allocator.nf_allocate(Wsize::new(*sz))))
+            .collect::<Vec<Value>>();
+        allocator.nf_deallocate(vals[1]);
+
+        let blocks = allocator.heap_walker().collect::<Vec<_>>();
+        // 2 pools, one of them has FFFF[30][20][10]
+        assert_eq!(blocks.len(), 5);
+        assert!(blocks.windows(2).all(|w| w[0].get_addr() < w[1].get_addr()));
+        assert_eq!(
+            blocks.iter().filter(|b| b.is_in_free_list()).count(),
+            FreeList::new(allocator.get_globals_mut()).nf_iter().count()
+        );
+        assert!(blocks
+            .iter()
+            .all(|b| b.is_in_free_list() == (b.get_header().get_color() == CAML_BLUE)));
+
+        let freed = blocks.iter().find(|b| b.get_addr() == vals[1].0).unwrap();
+        assert!(freed.is_in_free_list());
+        assert_eq!(freed.get_header().get_wosize(), Wsize::new(20));
+
+        let live = blocks.iter().find(|b| b.get_addr() == vals[2].0).unwrap();
+        assert!(!live.is_in_free_list());
+        assert_eq!(live.get_header().get_color(), CAML_BLACK);
+    }
+
+    #[test]
+    fn leak_report_test() {
+        let mut allocator = NfAllocator::new();
+        assert_eq!(allocator.leak_report(), Default::default());
+
+        allocator.nf_expand_heap(Wsize::new(10));
+        let vals = [10, 20, 10, 10]
+            .iter()
+            .map(|sz| val_hp!(allocator.nf_allocate(Wsize::new(*sz))))
+            .collect::<Vec<Value>>();
+        allocator.nf_deallocate(vals[2]);
+
+        // A zero sized fragment isn't a leak
+        let largest = FreeList::new(allocator.get_globals_mut())
+            .nf_iter()
+            .map(|it| it.get_cur().get_header().get_wosize())
+            .fold(Wsize::new(0), |acc, e| if e > acc { e } else { acc });
+        let rest = val_hp!(allocator.nf_allocate(largest - Wsize::new(1)));
+        allocator.nf_deallocate(rest);
+
+        let report = allocator.leak_report();
+        assert_eq!(report.blocks, 3);
+        assert_eq!(report.words, 11 + 21 + 11);
+        assert_eq!(report.groups.len(), 2);
+        assert_eq!(report.groups[0].wosize, 10);
+        assert_eq!(report.groups[0].count, 2);
+        assert_eq!(report.groups[0].addrs, vec![vals[3].0, vals[0].0]);
+        assert_eq!(report.groups[1].wosize, 20);
+        assert!(report
+            .to_string()
+            .starts_with("leak report: 3 blocks, 43 words"));
+
+        // After a sweep, whatever is still alive is CAML_WHITE and still counts
+        allocator.nf_sweep();
+        assert_eq!(allocator.leak_report().blocks, 3);
+        allocator.nf_sweep();
+        assert_eq!(allocator.leak_report().blocks, 0);
+    }
+
+    #[test]
+    fn sweep_slice_test() {
+        let mut allocator = NfAllocator::new();
+        allocator.nf_expand_heap(Wsize::new(10));
+        allocator.nf_expand_heap(Wsize::new(10));
+
+        // Enough to need both pools
+        let vals = (0..200)
+            .map(|_| val_hp!(allocator.nf_allocate(Wsize::new(1000))))
+            .collect::<Vec<Value>>();
+        assert_eq!(allocator.get_num_of_expansions(), 2);
+        for val in vals.iter().step_by(2) {
+            *val.get_header() = Header::new(1000, CAML_WHITE, DEFAULT_TAG);
+        }
+
+        assert_eq!(allocator.nf_sweep_slice(Wsize::new(100)), None);
+        allocator.nf_start_sweep();
+        assert!(allocator.is_sweeping());
+
+        // The mutator keeps allocating and freeing between the slices, both behind and ahead of
+        // where the sweep stopped
+        let mut live = vals
+            .iter()
+            .skip(1)
+            .step_by(2)
+            .copied()
+            .collect::<Vec<Value>>();
+        let mut reclaimed = None;
+        let mut slices = 0;
+        while reclaimed.is_none() {
+            reclaimed = allocator.nf_sweep_slice(Wsize::new(5000));
+            slices += 1;
+            let freed = live.swap_remove((slices * 7) % live.len());
+            allocator.nf_deallocate(freed);
+            live.push(val_hp!(allocator.nf_allocate(Wsize::new(3))));
+            assert!(allocator.heap_verify().is_ok());
+        }
+
+        assert!(slices > 10);
+        assert!(!allocator.is_sweeping());
+        assert!(*reclaimed.unwrap().get_val() >= 100 * 1001);
+        assert_eq!(allocator.quick_stat().sweeps, 1);
+        assert_eq!(allocator.heap_stats().live_blocks, live.len());
+        let report = allocator.heap_verify();
+        assert!(report.is_ok(), "{report:?}");
+    }
+
+    #[test]
+    fn lazy_sweep_test() {
+        let mut allocator = NfAllocator::new();
+        allocator.nf_expand_heap(Wsize::new(10));
+        allocator.nf_expand_heap(Wsize::new(10));
+        allocator.set_lazy_sweep(true);
+
+        let mut vals = vec![];
+        while allocator.get_num_of_expansions() == 2 {
+            let hp = allocator.nf_allocate(Wsize::new(1000));
+            if hp.is_null() {
+                break;
+            }
+            vals.push(val_hp!(hp));
+        }
+        // Both pools are full, half the blocks are garbage
+        for val in vals.iter().step_by(2) {
+            *val.get_header() = Header::new(1000, CAML_WHITE, DEFAULT_TAG);
+        }
+        allocator.nf_start_sweep();
+
+        // The first pool gets swept, not the second one
+        assert_ne!(
+            allocator.nf_allocate(Wsize::new(1000)),
+            std::ptr::null_mut()
+        );
+        assert!(allocator.is_sweeping());
+        assert_eq!(allocator.take_lazy_sweep_done(), None);
+        assert_eq!(allocator.quick_stat().sweeps, 0);
+
+        // Too big for any block freed so far, the rest of the sweep can't help either
+        assert_eq!(
+            allocator.nf_allocate(Wsize::new(5000)),
+            std::ptr::null_mut()
+        );
+        assert!(!allocator.is_sweeping());
+        assert!(*allocator.take_lazy_sweep_done().unwrap().get_val() >= vals.len() / 2 * 1001);
+        assert_eq!(allocator.get_num_of_expansions(), 2);
+        assert!(allocator.heap_verify().is_ok());
+    }
 }
@@ -1,4 +1,4 @@
-use crate::{header::Header, value::Value, word::Wsize};
+use crate::{header::Header, hp_val, val_hp, value::Value, word::Wsize};
 
 // Pool is a circular linked list(Doubly Linked List)
 #[repr(C)]
@@ -27,6 +27,13 @@ impl Pool {
             (*left).next = right;
         }
     }
+    pub fn unlink(pool: *mut Pool) {
+        unsafe {
+            (*(*pool).prev).next = (*pool).next;
+            (*(*pool).next).prev = (*pool).prev;
+        }
+    }
+
     pub fn get_next_raw(&self) -> *mut Pool {
         self.next
     }
@@ -40,6 +47,37 @@ impl Pool {
     pub fn get_prev_raw_from_raw(ptr: &*mut Pool) -> *mut Pool {
         unsafe { (**ptr).get_prev_raw() }
     }
+    pub fn get_wo_sz_from_raw(ptr: &*mut Pool) -> Wsize {
+        unsafe { (**ptr).pool_wo_sz }
+    }
+
+    // Blocks inside of the pool in address order, starting from the one whose header is hd
+    pub fn blocks(&self) -> PoolBlockIter {
+        PoolBlockIter {
+            cur_hp: std::ptr::addr_of!(self.hd) as *mut Header,
+            limit: std::ptr::addr_of!(*self) as usize + self.pool_wo_sz.to_bytesize(),
+        }
+    }
+}
+
+// The next block is found using the size of the current one at the time it's yielded, so the
+// header of the yielded value can be changed freely, as long as the memory it covers stays the
+// same
+pub struct PoolBlockIter {
+    cur_hp: *mut Header,
+    limit: usize,
+}
+
+impl Iterator for PoolBlockIter {
+    type Item = Value;
+    fn next(&mut self) -> Option<Self::Item> {
+        if (self.cur_hp as usize) >= self.limit {
+            return None;
+        }
+        let cur_val = val_hp!(self.cur_hp);
+        self.cur_hp = hp_val!(cur_val.get_next_from_size());
+        Some(cur_val)
+    }
 }
 
 pub struct PoolIter<'a> {
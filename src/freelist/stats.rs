@@ -0,0 +1,177 @@
+use std::fmt::{self, Display};
+
+use crate::{colors::CAML_BLUE, utils::whsize_wosize, word::Wsize};
+
+use super::allocator::NfAllocator;
+
+// Modeled after OCaml's Gc.stat. All the sizes are in words and, unless mentioned otherwise,
+// include the header of the blocks.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    // Total size of all the pools, including the Pool struct at the start of each one
+    pub heap_words: usize,
+    // NfGlobals::cur_wsz, whsize of the free blocks
+    pub free_words: usize,
+    // Words in CAML_BLACK/CAML_WHITE blocks, zero sized fragments excluded
+    pub live_words: usize,
+    pub live_blocks: usize,
+    pub free_blocks: usize,
+    // wosize of the largest CAML_BLUE block
+    pub largest_free: usize,
+    // Zero wosize headers left behind by nf_allocate_block and sweep
+    pub fragments: usize,
+    pub pools: usize,
+    pub heap_expansions: usize,
+    // Largest value heap_words has ever had
+    pub top_heap_words: usize,
+}
+
+// Same as HeapStats but only made of what the allocator keeps track of as it goes, so it's
+// cheap enough to be read at any time. Like OCaml's Gc.quick_stat
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct QuickStat {
+    pub free_words: usize,
+    pub allocated_words: usize,
+    pub allocations: usize,
+    pub frees: usize,
+    pub swept_words: usize,
+    pub sweeps: usize,
+    pub fl_visited: usize,
+    pub heap_expansions: usize,
+    pub top_heap_words: usize,
+}
+
+pub const HISTOGRAM_BUCKETS: usize = usize::BITS as usize;
+
+// Free blocks bucketed by their wosize, bucket i has the blocks with wosize in [2^i, 2^(i+1)).
+// Blocks of wosize 0 end up in bucket 0 as well
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FragmentationReport {
+    pub histogram: [usize; HISTOGRAM_BUCKETS],
+    pub free_blocks: usize,
+    // Sum of wosize of all the free blocks. Unlike HeapStats::free_words, headers aren't counted
+    pub free_wosize: usize,
+    pub largest_free: usize,
+    // 1 - largest_free/free_wosize. 0 when all the free memory is one block(or there's none),
+    // close to 1 when it's scattered over many small ones
+    pub external_fragmentation: f64,
+    // Headers of size 0 which nf_allocate_block leaves behind when a block is one word bigger
+    // than the request. Each one is a word that can't be used until its neighbours are freed
+    pub zero_wosize_headers: usize,
+}
+
+impl Default for FragmentationReport {
+    fn default() -> Self {
+        FragmentationReport {
+            histogram: [0; HISTOGRAM_BUCKETS],
+            free_blocks: 0,
+            free_wosize: 0,
+            largest_free: 0,
+            external_fragmentation: 0.0,
+            zero_wosize_headers: 0,
+        }
+    }
+}
+
+impl FragmentationReport {
+    pub fn bucket_of(wo_sz: usize) -> usize {
+        (usize::BITS - 1).saturating_sub(wo_sz.leading_zeros()) as usize
+    }
+}
+
+impl Display for FragmentationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "free blocks: {}, free wosize: {}, largest free: {}, zero sized headers: {}",
+            self.free_blocks, self.free_wosize, self.largest_free, self.zero_wosize_headers
+        )?;
+        writeln!(
+            f,
+            "external fragmentation: {:.4}",
+            self.external_fragmentation
+        )?;
+        for (i, count) in self.histogram.iter().enumerate().filter(|(_, c)| **c != 0) {
+            writeln!(f, "  [{}, {}): {}", 1usize << i, 1u128 << (i + 1), count)?;
+        }
+        Ok(())
+    }
+}
+
+impl NfAllocator {
+    pub fn quick_stat(&self) -> QuickStat {
+        let globals = self.get_globals();
+        let counters = &globals.counters;
+        QuickStat {
+            free_words: *globals.cur_wsz.get_val(),
+            allocated_words: *counters.allocated_wsz.get_val(),
+            allocations: counters.num_allocs,
+            frees: counters.num_frees,
+            swept_words: *counters.swept_wsz.get_val(),
+            sweeps: counters.num_sweeps,
+            fl_visited: counters.fl_visited,
+            heap_expansions: self.get_num_of_expansions(),
+            top_heap_words: *self.get_top_heap_wsz().get_val(),
+        }
+    }
+
+    pub fn heap_stats(&self) -> HeapStats {
+        let mut stats = HeapStats {
+            free_words: *self.get_globals().cur_wsz.get_val(),
+            heap_expansions: self.get_num_of_expansions(),
+            top_heap_words: *self.get_top_heap_wsz().get_val(),
+            ..Default::default()
+        };
+
+        for it in self.get_pool_iter() {
+            let pool = it.get_pool();
+            stats.pools += 1;
+            stats.heap_words += *pool.pool_wo_sz.get_val();
+
+            for val in pool.blocks() {
+                let hd = val.get_header();
+                let wo_sz = hd.get_wosize();
+                if hd.get_color() == CAML_BLUE {
+                    stats.free_blocks += 1;
+                    stats.largest_free = stats.largest_free.max(*wo_sz.get_val());
+                } else if wo_sz == Wsize::new(0) {
+                    stats.fragments += 1;
+                } else {
+                    stats.live_blocks += 1;
+                    stats.live_words += *whsize_wosize(wo_sz).get_val();
+                }
+            }
+        }
+
+        stats
+    }
+
+    pub fn fragmentation(&self) -> FragmentationReport {
+        let mut report = FragmentationReport::default();
+
+        for it in self.get_pool_iter() {
+            for val in it.get_pool().blocks() {
+                let hd = val.get_header();
+                let wo_sz = *hd.get_wosize().get_val();
+                if hd.get_color() == CAML_BLUE {
+                    report.free_blocks += 1;
+                    report.free_wosize += wo_sz;
+                    report.largest_free = report.largest_free.max(wo_sz);
+                    report.histogram[FragmentationReport::bucket_of(wo_sz)] += 1;
+                } else if wo_sz == 0 {
+                    report.zero_wosize_headers += 1;
+                }
+            }
+        }
+
+        if report.free_wosize != 0 {
+            report.external_fragmentation =
+                1.0 - report.largest_free as f64 / report.free_wosize as f64;
+        }
+
+        report
+    }
+}
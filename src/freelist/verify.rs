@@ -0,0 +1,137 @@
+use crate::{
+    colors::{CAML_BLACK, CAML_BLUE, CAML_WHITE},
+    hd_hp,
+    header::Header,
+    hp_val,
+    utils::whsize_wosize,
+    val_hp,
+    value::Value,
+};
+
+use super::{allocator::NfAllocator, pool::Pool};
+
+// Result of a full walk over the heap. Every field suffixed with an error name counts the number
+// of places where that invariant was found broken, so a healthy heap has all of them at 0.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HeapVerifyReport {
+    pub pools: usize,
+    pub blocks: usize,
+    pub free_blocks: usize,
+    pub free_list_len: usize,
+    // Sum of whsize of every CAML_BLUE block found while walking the pools
+    pub free_wsz: usize,
+    // What NfGlobals::cur_wsz claims the free list holds
+    pub cur_wsz: usize,
+
+    // Pools whose prev/next links dont point back to them or which aren't sorted by address
+    pub bad_pool_links: usize,
+    // Pools in which the block sizes don't add up to exactly the pool size
+    pub untiled_pools: usize,
+    // Headers with CAML_GRAY, which must never be seen outside of a mark phase
+    pub bad_colors: usize,
+    // CAML_BLUE blocks which can't be reached from nf_head
+    pub free_blocks_not_in_fl: usize,
+    // Free list entries which aren't a CAML_BLUE block inside one of the pools
+    pub fl_entries_not_free: usize,
+    // Free list entries whose address isn't greater than the one before it
+    pub fl_out_of_order: usize,
+    // CAML_BLUE blocks that lie right after another CAML_BLUE block
+    pub unmerged_free_blocks: usize,
+    // free_wsz != cur_wsz
+    pub cur_wsz_mismatch: bool,
+    // pools != num_pools
+    pub pool_count_mismatch: bool,
+}
+
+impl HeapVerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.bad_pool_links == 0
+            && self.untiled_pools == 0
+            && self.bad_colors == 0
+            && self.free_blocks_not_in_fl == 0
+            && self.fl_entries_not_free == 0
+            && self.fl_out_of_order == 0
+            && (cfg!(feature = "no_merge") || self.unmerged_free_blocks == 0)
+            && !self.cur_wsz_mismatch
+            && !self.pool_count_mismatch
+    }
+}
+
+impl NfAllocator {
+    // Unlike verify_nf_last_invariant and check_pool_list_invariant, this never asserts and doesn't
+    // touch any of the globals(nf_iter would update nf_last), so it's safe to call at any point
+    // from outside of the allocator.
+    pub fn heap_verify(&self) -> HeapVerifyReport {
+        let mut report = HeapVerifyReport {
+            cur_wsz: *self.get_globals().cur_wsz.get_val(),
+            ..Default::default()
+        };
+
+        let mut fl = self.collect_free_list();
+        report.free_list_len = fl.len();
+        report.fl_out_of_order = fl.windows(2).filter(|w| w[1] <= w[0]).count();
+        fl.sort_unstable_by_key(|v| v.0);
+
+        let pool_head = self.get_globals().pool_head;
+        let mut prev_pool = pool_head;
+        for it in self.get_pool_iter() {
+            let pool = it.get_pool();
+            let pool_ptr = std::ptr::addr_of!(*pool) as *mut Pool;
+
+            report.pools += 1;
+            if Pool::get_next_raw_from_raw(&prev_pool) != pool_ptr
+                || Pool::get_prev_raw_from_raw(&pool_ptr) != prev_pool
+                || (prev_pool != pool_head && prev_pool >= pool_ptr)
+            {
+                report.bad_pool_links += 1;
+            }
+            prev_pool = pool_ptr;
+
+            self.verify_pool(pool, &fl, &mut report);
+        }
+
+        report.cur_wsz_mismatch = report.free_wsz != report.cur_wsz;
+        report.pool_count_mismatch = report.pools != self.get_num_pools();
+        report.fl_entries_not_free = report.free_list_len
+            - (report.free_blocks - report.free_blocks_not_in_fl).min(report.free_list_len);
+
+        report
+    }
+
+    fn verify_pool(&self, pool: &Pool, fl: &[Value], report: &mut HeapVerifyReport) {
+        let mut cur_hp = std::ptr::addr_of!(pool.hd) as *mut Header;
+        let limit = std::ptr::addr_of!(*pool) as usize + pool.pool_wo_sz.to_bytesize();
+
+        let mut prev_was_free = false;
+        while (cur_hp as usize) < limit {
+            let cur_hd = hd_hp!(cur_hp);
+            let cur_val = val_hp!(cur_hp);
+            report.blocks += 1;
+
+            match cur_hd.get_color() {
+                CAML_BLUE => {
+                    report.free_blocks += 1;
+                    report.free_wsz += *whsize_wosize(cur_hd.get_wosize()).get_val();
+                    if fl.binary_search_by_key(&cur_val.0, |v| v.0).is_err() {
+                        report.free_blocks_not_in_fl += 1;
+                    }
+                    if prev_was_free {
+                        report.unmerged_free_blocks += 1;
+                    }
+                    prev_was_free = true;
+                }
+                CAML_BLACK | CAML_WHITE => prev_was_free = false,
+                _ => {
+                    report.bad_colors += 1;
+                    prev_was_free = false;
+                }
+            }
+            cur_hp = hp_val!(cur_val.get_next_from_size());
+        }
+
+        if cur_hp as usize != limit {
+            report.untiled_pools += 1;
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use crate::{header::Header, value::Value};
+
+use super::{
+    allocator::NfAllocator,
+    pool::{PoolBlockIter, PoolIter},
+};
+
+// Visits every block in every pool in address order, the same walk that NfAllocator::sweep does.
+// Free list membership is worked out once up front, so the heap must not be changed while
+// walking it.
+pub struct HeapWalker<'a> {
+    pools: PoolIter<'a>,
+    blocks: Option<PoolBlockIter>,
+    // sorted by address
+    fl: Vec<Value>,
+}
+
+#[derive(Debug)]
+pub struct HeapWalkerVal {
+    val: Value,
+    header: Header,
+    in_free_list: bool,
+}
+
+impl HeapWalkerVal {
+    // Address of the first field of the block, same as what alloc returns
+    #[inline(always)]
+    pub fn get_addr(&self) -> usize {
+        self.val.0
+    }
+    #[inline(always)]
+    pub fn get_header(&self) -> &Header {
+        &self.header
+    }
+    #[inline(always)]
+    pub fn is_in_free_list(&self) -> bool {
+        self.in_free_list
+    }
+}
+
+impl<'a> HeapWalker<'a> {
+    pub fn new(allocator: &'a NfAllocator) -> Self {
+        let mut fl = allocator.collect_free_list();
+        fl.sort_unstable_by_key(|v| v.0);
+        Self {
+            pools: allocator.get_pool_iter(),
+            blocks: None,
+            fl,
+        }
+    }
+}
+
+impl Iterator for HeapWalker<'_> {
+    type Item = HeapWalkerVal;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(val) = self.blocks.as_mut().and_then(|it| it.next()) {
+                return Some(HeapWalkerVal {
+                    val,
+                    header: val.get_header().clone(),
+                    in_free_list: self.fl.binary_search_by_key(&val.0, |v| v.0).is_ok(),
+                });
+            }
+            self.blocks = Some(self.pools.next()?.get_pool().blocks());
+        }
+    }
+}
+
+impl NfAllocator {
+    pub fn heap_walker(&self) -> HeapWalker<'_> {
+        HeapWalker::new(self)
+    }
+}
@@ -0,0 +1,149 @@
+use crate::{
+    freelist::allocator::NfAllocator,
+    utils::{field_ref_mut, get_actual_wosz_to_request},
+    value::Value,
+    word::Wsize,
+};
+
+use super::{mark::Marker, roots::Roots, weak::WeakRefs};
+
+// State of the collector that has to outlive a single call, i.e what an incremental mark needs
+// between two slices.
+pub struct Collector {
+    // Some while a mark is in progress
+    marker: Option<Marker>,
+    // Words alloc sweeps for every word it allocates while there's a sweep in progress
+    pub sweep_work_per_word: usize,
+    // Like OCaml's Gc.space_overhead, a percentage of the live heap. 0 turns off starting cycles
+    // from alloc
+    space_overhead: usize,
+    // Live words are never taken as less than this, set along with space_overhead since it comes
+    // from the environment
+    min_live: usize,
+    // quick_stat().allocated_words and the words that weren't free when the last sweep was over
+    allocated_at_sweep: usize,
+    live_at_sweep: usize,
+}
+
+impl Collector {
+    pub const fn new() -> Self {
+        Collector {
+            marker: None,
+            sweep_work_per_word: 0,
+            space_overhead: 0,
+            min_live: 0,
+            allocated_at_sweep: 0,
+            live_at_sweep: 0,
+        }
+    }
+
+    pub fn is_marking(&self) -> bool {
+        self.marker.is_some()
+    }
+
+    pub fn start_marking(&mut self, allocator: &NfAllocator, roots: &Roots) {
+        // The blocks the sweep in progress hasn't got to yet would be freed even if marked
+        debug_assert!(!allocator.is_sweeping(), "Marking before the sweep is over");
+        let mut marker = Marker::new(allocator);
+        marker.mark_roots(roots);
+        self.marker = Some(marker);
+    }
+
+    // Does about `budget` words of marking work, starting a new mark if none is in progress.
+    // Returns true once the mark is over, at which point it's ok to sweep.
+    //
+    // Roots aren't behind a write barrier, so once the gray stack is empty they are scanned once
+    // more. Then the data of the ephemerons with a reachable key is darkened. The mark is only over
+    // when neither turns anything gray.
+    pub fn mark_slice(
+        &mut self,
+        allocator: &NfAllocator,
+        roots: &Roots,
+        weak: &WeakRefs,
+        budget: usize,
+    ) -> bool {
+        if self.marker.is_none() {
+            self.start_marking(allocator, roots);
+        }
+        let marker = self.marker.as_mut().unwrap();
+
+        marker.mark_slice(budget);
+        if marker.is_done() {
+            marker.mark_roots(roots);
+            if marker.is_done() && !marker.mark_ephemerons(weak) {
+                self.marker = None;
+                return true;
+            }
+        }
+        false
+    }
+
+    // Runs the mark in progress, or a new one, to completion
+    pub fn finish_marking(&mut self, allocator: &NfAllocator, roots: &Roots, weak: &WeakRefs) {
+        while !self.mark_slice(allocator, roots, weak, usize::MAX) {}
+    }
+
+    // Dijkstra style insertion barrier. Every store of a pointer into a block has to go through
+    // this while a mark is in progress, otherwise a white block could end up only referenced from
+    // a black one, which is never scanned again.
+    pub fn write_barrier(&mut self, obj: Value, field: usize, new: Value) {
+        *field_ref_mut(&obj, field as isize) = new;
+        self.darken(new);
+    }
+
+    // Keeps `v` alive through the mark in progress, if there's one
+    pub fn darken(&mut self, v: Value) {
+        if let Some(marker) = self.marker.as_mut() {
+            marker.darken(v);
+        }
+    }
+
+    // Has to be called on every value read out of a weak table or an ephemeron. Otherwise a white
+    // block could get stored in a block that's already black, and be reclaimed while reachable
+    pub fn read_barrier(&mut self, v: Value) -> Value {
+        self.darken(v);
+        v
+    }
+
+    pub fn on_sweep_done(&mut self, allocator: &NfAllocator) {
+        let stat = allocator.quick_stat();
+        self.allocated_at_sweep = stat.allocated_words;
+        self.live_at_sweep = *allocator.get_heap_wsz().get_val() - stat.free_words;
+    }
+
+    // 0 turns off starting cycles from alloc
+    pub fn set_space_overhead(&mut self, percent: usize) {
+        self.space_overhead = percent;
+        self.min_live = *get_actual_wosz_to_request(Wsize::new(0)).get_val();
+    }
+
+    // True once the words allocated since the last sweep are more than space_overhead percent of
+    // the words that were live after it. A heap smaller than one pool is taken as one pool big, so
+    // that a nearly empty heap isn't collected on every alloc
+    pub fn should_start_cycle(&self, allocator: &NfAllocator) -> bool {
+        if self.space_overhead == 0 || self.is_marking() || allocator.is_sweeping() {
+            return false;
+        }
+        let allocated = allocator.quick_stat().allocated_words - self.allocated_at_sweep;
+        let live = self.live_at_sweep.max(self.min_live);
+        allocated.saturating_mul(100) >= live.saturating_mul(self.space_overhead)
+    }
+
+    pub fn on_dealloc(&mut self, v: Value) {
+        if let Some(marker) = self.marker.as_mut() {
+            marker.forget_block(v);
+        }
+    }
+}
+
+impl Default for Collector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static mut COLLECTOR: Collector = Collector::new();
+
+pub fn get_collector() -> &'static mut Collector {
+    unsafe { &mut *std::ptr::addr_of_mut!(COLLECTOR) }
+}
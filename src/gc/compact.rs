@@ -0,0 +1,41 @@
+use crate::{
+    freelist::{allocator::NfAllocator, compact::Forwarding},
+    utils::field_ref_mut,
+    value::Value,
+};
+
+use super::{
+    mark::{find_block, first_scanned_field},
+    roots::Roots,
+};
+
+// New address of whatever `v` points to, infix pointers included
+fn forward(forwarding: &Forwarding, v: Value) -> Option<Value> {
+    let block = find_block(forwarding.old_blocks(), v)?;
+    let new = forwarding.forward(block)?;
+    Some(Value(new.0 + (v.0 - block.0)))
+}
+
+impl NfAllocator {
+    // Compacts the heap, rewriting the registered roots and the scanned fields of every allocated
+    // block to point to where the blocks moved. Pointers kept anywhere else are left dangling.
+    pub fn compact(&mut self, roots: &Roots) -> Forwarding {
+        self.nf_compact(|forwarding| {
+            for slot in roots.slots() {
+                if let Some(new) = forward(forwarding, unsafe { *slot }) {
+                    unsafe { *slot = new };
+                }
+            }
+            for old in forwarding.old_blocks() {
+                let val = Value(*old);
+                let wo_sz = *val.get_header().get_wosize().get_val();
+                for i in first_scanned_field(val)..wo_sz {
+                    let field = field_ref_mut(&val, i as isize);
+                    if let Some(new) = forward(forwarding, *field) {
+                        *field = new;
+                    }
+                }
+            }
+        })
+    }
+}
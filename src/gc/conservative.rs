@@ -0,0 +1,126 @@
+use std::cell::Cell;
+
+use crate::utils::WORD_SIZE;
+
+// Highest address of the current thread's stack
+#[cfg(target_os = "linux")]
+fn stack_top() -> Option<usize> {
+    use std::ffi::{c_int, c_void};
+
+    // Opaque, big enough for glibc's and musl's pthread_attr_t
+    #[repr(C, align(16))]
+    struct PthreadAttr([u8; 64]);
+
+    extern "C" {
+        fn pthread_self() -> usize;
+        fn pthread_getattr_np(thread: usize, attr: *mut PthreadAttr) -> c_int;
+        fn pthread_attr_getstack(
+            attr: *const PthreadAttr,
+            addr: *mut *mut c_void,
+            size: *mut usize,
+        ) -> c_int;
+        fn pthread_attr_destroy(attr: *mut PthreadAttr) -> c_int;
+    }
+
+    let mut attr = PthreadAttr([0; 64]);
+    let mut addr = std::ptr::null_mut();
+    let mut size = 0;
+    unsafe {
+        if pthread_getattr_np(pthread_self(), &mut attr) != 0 {
+            return None;
+        }
+        let res = pthread_attr_getstack(&attr, &mut addr, &mut size);
+        pthread_attr_destroy(&mut attr);
+        (res == 0).then_some(addr as usize + size)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn stack_top() -> Option<usize> {
+    None
+}
+
+thread_local! {
+    // pthread_getattr_np reads /proc/self/maps for the main thread, so it's only asked once
+    static STACK_TOP: Cell<Option<Option<usize>>> = const { Cell::new(None) };
+}
+
+// Callee saved registers, the ones a pointer could be kept in across the call into the allocator
+// without ever being spilled to the stack
+#[inline(always)]
+fn callee_saved_registers() -> Vec<usize> {
+    #[cfg(target_arch = "x86_64")]
+    {
+        let mut regs = [0usize; 6];
+        unsafe {
+            std::arch::asm!(
+                "mov [{0}], rbx",
+                "mov [{0} + 8], rbp",
+                "mov [{0} + 16], r12",
+                "mov [{0} + 24], r13",
+                "mov [{0} + 32], r14",
+                "mov [{0} + 40], r15",
+                in(reg) regs.as_mut_ptr(),
+                options(nostack, preserves_flags),
+            );
+        }
+        regs.to_vec()
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        let mut regs = [0usize; 12];
+        unsafe {
+            std::arch::asm!(
+                "stp x19, x20, [{0}]",
+                "stp x21, x22, [{0}, #16]",
+                "stp x23, x24, [{0}, #32]",
+                "stp x25, x26, [{0}, #48]",
+                "stp x27, x28, [{0}, #64]",
+                "stp x29, x30, [{0}, #80]",
+                in(reg) regs.as_mut_ptr(),
+                options(nostack, preserves_flags),
+            );
+        }
+        regs.to_vec()
+    }
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        vec![]
+    }
+}
+
+// Calls `f` with the callee saved registers and every word of the current thread's stack, from
+// this call's frame up to the top. Only the registers are scanned if the top of the stack can't
+// be found, i.e anywhere but Linux
+#[inline(never)]
+pub fn scan_stack(mut f: impl FnMut(usize)) {
+    for reg in callee_saved_registers() {
+        f(reg);
+    }
+
+    let top = STACK_TOP.with(|top| match top.get() {
+        Some(cached) => cached,
+        None => {
+            let found = stack_top();
+            top.set(Some(found));
+            found
+        }
+    });
+    let Some(top) = top else {
+        return;
+    };
+    let marker = 0usize;
+    let bottom = std::hint::black_box(&marker) as *const usize as usize;
+    scan_range(bottom, top - bottom, f);
+}
+
+// Calls `f` with every aligned word in [start, start + len)
+pub fn scan_range(start: usize, len: usize, mut f: impl FnMut(usize)) {
+    let first = start.next_multiple_of(WORD_SIZE);
+    for addr in (first..start + len).step_by(WORD_SIZE) {
+        if addr + WORD_SIZE > start + len {
+            break;
+        }
+        f(unsafe { std::ptr::read_volatile(addr as *const usize) });
+    }
+}
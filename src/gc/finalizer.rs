@@ -0,0 +1,139 @@
+use std::collections::BTreeMap;
+
+use crate::{colors::CAML_WHITE, utils::field_ref_mut, value::Value};
+
+use super::mark::first_scanned_field;
+
+// Called with the block itself, right before the sweep reclaims it. Everything the block points to
+// is still there at that point, but the pointer must not be kept around
+pub type FinalizerFirst = extern "C" fn(ptr: *mut u8);
+// Called once the sweep that reclaimed the block is over, with what was in its header
+pub type FinalizerLast = extern "C" fn(wo_sz: usize, tag: u8);
+
+#[derive(Clone, Copy)]
+enum Finalizer {
+    First(FinalizerFirst),
+    Last(FinalizerLast),
+}
+
+// Like OCaml's Gc.finalise and Gc.finalise_last. A block can have any number of finalizers, they
+// run in the order they were registered in. Finalizers must not call back into the allocator.
+pub struct Finalizers {
+    // Block address -> its finalizers, so that freeing a block without any is cheap
+    registered: BTreeMap<usize, Vec<Finalizer>>,
+    // Last style finalizers of blocks the sweep in progress is reclaiming, with their wosize and tag
+    pending: Vec<(FinalizerLast, usize, u8)>,
+}
+
+impl Finalizers {
+    pub const fn new() -> Self {
+        Finalizers {
+            registered: BTreeMap::new(),
+            pending: vec![],
+        }
+    }
+
+    pub fn register_first(&mut self, val: Value, f: FinalizerFirst) {
+        self.register(val, Finalizer::First(f));
+    }
+
+    pub fn register_last(&mut self, val: Value, f: FinalizerLast) {
+        self.register(val, Finalizer::Last(f));
+    }
+
+    fn register(&mut self, val: Value, f: Finalizer) {
+        self.registered.entry(val.0).or_default().push(f);
+    }
+
+    pub fn num_registered(&self) -> usize {
+        self.registered.values().map(|fs| fs.len()).sum()
+    }
+
+    // Has to be called before a sweep starts. Runs the first style finalizers of the blocks the
+    // sweep is going to reclaim, and keeps the last style ones for after_sweep
+    pub fn before_sweep(&mut self) {
+        let (dead, alive): (BTreeMap<_, _>, _) = std::mem::take(&mut self.registered)
+            .into_iter()
+            .partition(|(addr, _)| Value(*addr).get_header().get_color() == CAML_WHITE);
+        self.registered = alive;
+
+        for (val, f) in dead
+            .into_iter()
+            .flat_map(|(addr, fs)| fs.into_iter().map(move |f| (Value(addr), f)))
+        {
+            match f {
+                Finalizer::First(f) => f(val.0 as *mut u8),
+                Finalizer::Last(f) => {
+                    let hd = val.get_header();
+                    self.pending
+                        .push((f, *hd.get_wosize().get_val(), hd.get_tag()));
+                }
+            }
+        }
+    }
+
+    pub fn after_sweep(&mut self) {
+        for (f, wo_sz, tag) in std::mem::take(&mut self.pending) {
+            f(wo_sz, tag);
+        }
+    }
+
+    // A block freed with dealloc doesn't get finalized, the address may be handed out again
+    pub fn on_dealloc(&mut self, val: Value) {
+        self.registered.remove(&val.0);
+    }
+
+    // After a minor collection, `forward` gives the new address of a promoted block and None for
+    // a young block that wasn't promoted. The finalizers of those run right away, while the block
+    // is still in the minor heap. The young blocks it points to may have been promoted, leaving
+    // only a forwarding header behind, so its fields are pointed to the copies first
+    pub fn minor_collected(&mut self, forward: impl Fn(Value) -> Option<Value>) {
+        let mut dead = vec![];
+        for (addr, fs) in std::mem::take(&mut self.registered) {
+            match forward(Value(addr)) {
+                Some(val) => self.registered.entry(val.0).or_default().extend(fs),
+                None => dead.extend(fs.into_iter().map(|f| (Value(addr), f))),
+            }
+        }
+
+        let mut last = vec![];
+        for (val, f) in dead {
+            match f {
+                Finalizer::First(f) => {
+                    for i in first_scanned_field(val)..*val.get_header().get_wosize().get_val() {
+                        let field = field_ref_mut(&val, i as isize);
+                        *field = forward(*field).unwrap_or(*field);
+                    }
+                    f(val.0 as *mut u8)
+                }
+                Finalizer::Last(f) => {
+                    let hd = val.get_header();
+                    last.push((f, *hd.get_wosize().get_val(), hd.get_tag()));
+                }
+            }
+        }
+        for (f, wo_sz, tag) in last {
+            f(wo_sz, tag);
+        }
+    }
+
+    // Blocks moved by a compaction, `forward` gives the new address of a block
+    pub fn relocate(&mut self, forward: impl Fn(Value) -> Option<Value>) {
+        for (addr, fs) in std::mem::take(&mut self.registered) {
+            let val = forward(Value(addr)).unwrap_or(Value(addr));
+            self.registered.entry(val.0).or_default().extend(fs);
+        }
+    }
+}
+
+impl Default for Finalizers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static mut FINALIZERS: Finalizers = Finalizers::new();
+
+pub fn get_finalizers() -> &'static mut Finalizers {
+    unsafe { &mut *std::ptr::addr_of_mut!(FINALIZERS) }
+}
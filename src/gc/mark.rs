@@ -0,0 +1,231 @@
+use crate::{
+    colors::{CAML_BLACK, CAML_BLUE, CAML_GRAY, CAML_WHITE},
+    freelist::allocator::NfAllocator,
+    header::Header,
+    tags::{is_long, start_env_closinfo, CLOSURE_TAG, INFIX_TAG, NO_SCAN_TAG},
+    utils::{field_ref_mut, WORD_SIZE},
+    value::Value,
+};
+
+use super::{roots::Roots, weak::WeakRefs};
+
+// Tri-color marking over the blocks of an NfAllocator. Creating a Marker whitens every allocated
+// block, after that roots and fields that point to a white block turn it gray and push it on the
+// gray stack. Scanning a gray block turns it black. Once the gray stack is empty, every white
+// block left is unreachable and nf_sweep will reclaim it.
+pub struct Marker {
+    // Addresses of all the allocated blocks when marking started, sorted. A word is only taken as
+    // a pointer if it's exactly one of these, or an infix pointer into one of the closures
+    blocks: Vec<usize>,
+    // Set for the entries of `blocks` that were freed since, which are then treated as not there
+    freed: Vec<bool>,
+    gray: Vec<Value>,
+}
+
+// The block that has to be kept alive if `v` is reachable, `blocks` being the sorted addresses of
+// all the allocated blocks. That's `v` itself, or for a pointer to an infix block the closure it
+// lives in. Like in OCaml, the wosize of an infix header is its offset in words from the start of
+// the enclosing closure.
+pub fn find_block(blocks: &[usize], v: Value) -> Option<Value> {
+    if is_long(v.0) {
+        return None;
+    }
+    let closure = match blocks.binary_search(&v.0) {
+        Ok(_) => return Some(v),
+        Err(0) => return None,
+        Err(i) => Value(blocks[i - 1]),
+    };
+
+    let hd = closure.get_header();
+    let offset = (v.0 - closure.0) / WORD_SIZE;
+    if hd.get_tag() != CLOSURE_TAG
+        || !(v.0 - closure.0).is_multiple_of(WORD_SIZE)
+        || offset >= *hd.get_wosize().get_val()
+    {
+        return None;
+    }
+    let infix_hd = v.get_header();
+    (infix_hd.get_tag() == INFIX_TAG && *infix_hd.get_wosize().get_val() == offset)
+        .then_some(closure)
+}
+
+// Sorted addresses of the allocated blocks of `allocator`, free blocks and zero sized fragments
+// left out
+pub fn allocated_blocks(allocator: &NfAllocator) -> Vec<usize> {
+    let mut blocks = vec![];
+    for it in allocator.get_pool_iter() {
+        for val in it.get_pool().blocks() {
+            let hd = val.get_header();
+            if hd.get_color() != CAML_BLUE && *hd.get_wosize().get_val() != 0 {
+                blocks.push(val.0);
+            }
+        }
+    }
+    blocks
+}
+
+// The block `addr` points inside of, `blocks` being sorted block addresses as given by
+// allocated_blocks. Headers don't count, and an infix pointer gives the closure it's in
+pub fn block_containing(blocks: &[usize], addr: usize) -> Option<Value> {
+    let i = blocks.partition_point(|b| *b <= addr);
+    let block = Value(blocks[i.checked_sub(1)?]);
+    (addr < block.0 + *block.get_header().get_wosize().get_val() * WORD_SIZE).then_some(block)
+}
+
+// Index of the first field of `v` that holds a value
+pub fn first_scanned_field(v: Value) -> usize {
+    let hd = v.get_header();
+    let wo_sz = *hd.get_wosize().get_val();
+    match hd.get_tag() {
+        tag if tag >= NO_SCAN_TAG => wo_sz,
+        CLOSURE_TAG if wo_sz > 1 => start_env_closinfo(field_ref_mut(&v, 1).0).min(wo_sz),
+        _ => 0,
+    }
+}
+
+fn set_color(val: Value, color: usize) {
+    let hd = val.get_header();
+    *hd = Header::new(*hd.get_wosize().get_val(), color, hd.get_tag());
+}
+
+impl Marker {
+    pub fn new(allocator: &NfAllocator) -> Self {
+        // Zero sized fragments are never reachable, and never need to be whitened
+        let blocks = allocated_blocks(allocator);
+        for block in &blocks {
+            set_color(Value(*block), CAML_WHITE);
+        }
+
+        Marker {
+            freed: vec![false; blocks.len()],
+            blocks,
+            gray: vec![],
+        }
+    }
+
+    pub fn is_block(&self, v: Value) -> bool {
+        self.blocks
+            .binary_search(&v.0)
+            .is_ok_and(|i| !self.freed[i])
+    }
+
+    // find_block, leaving out the blocks freed since marking started
+    fn find(&self, v: Value) -> Option<Value> {
+        find_block(&self.blocks, v).filter(|block| self.is_block(*block))
+    }
+
+    // If v points to a white block, it becomes gray and gets pushed on the gray stack
+    pub fn darken(&mut self, v: Value) {
+        if let Some(block) = self.find(v) {
+            self.darken_block(block);
+        }
+    }
+
+    fn darken_block(&mut self, block: Value) {
+        if block.get_header().get_color() == CAML_WHITE {
+            set_color(block, CAML_GRAY);
+            self.gray.push(block);
+        }
+    }
+
+    // The words of the roots' ranges and of the stack keep alive any block they point inside of
+    pub fn mark_roots(&mut self, roots: &Roots) {
+        for slot in roots.slots() {
+            self.darken(unsafe { *slot });
+        }
+        roots.scan_ambiguous(|word| {
+            // Only the blocks there when marking started can be white, so the blocks allocated
+            // since don't matter
+            if let Some(block) = block_containing(&self.blocks, word) {
+                if self.is_block(block) {
+                    self.darken_block(block);
+                }
+            }
+        });
+    }
+
+    // Returns the whsize of `v`, which is what a scan counts as work
+    fn scan(&mut self, v: Value) -> usize {
+        let wo_sz = *v.get_header().get_wosize().get_val();
+        for i in first_scanned_field(v)..wo_sz {
+            self.darken(*field_ref_mut(&v, i as isize));
+        }
+        set_color(v, CAML_BLACK);
+        wo_sz + 1
+    }
+
+    // Darkens the data of every ephemeron whose key is reachable, i.e not a white block. Returns
+    // true if that turned anything gray, in which case the data has to be scanned and this called
+    // again, as it may make more keys reachable
+    pub fn mark_ephemerons(&mut self, weak: &WeakRefs) -> bool {
+        let num_gray = self.gray.len();
+        for (key, data) in weak.ephemerons() {
+            let key_alive = self
+                .find(key)
+                .is_none_or(|block| block.get_header().get_color() != CAML_WHITE);
+            if key_alive {
+                self.darken(data);
+            }
+        }
+        self.gray.len() != num_gray
+    }
+
+    // Has to be called for every block freed while marking is in progress. Once merged, the
+    // memory of `v` can be handed out again as blocks at other addresses, and its address must
+    // not be taken for a block, or scanned as one, anymore. If it's on the gray stack, it's
+    // skipped when popped
+    pub fn forget_block(&mut self, v: Value) {
+        if let Ok(i) = self.blocks.binary_search(&v.0) {
+            self.freed[i] = true;
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.gray.is_empty()
+    }
+
+    fn pop_gray(&mut self) -> Option<Value> {
+        while let Some(v) = self.gray.pop() {
+            if self.is_block(v) {
+                return Some(v);
+            }
+        }
+        None
+    }
+
+    // Scans gray blocks until there's none left
+    pub fn drain(&mut self) {
+        while let Some(v) = self.pop_gray() {
+            self.scan(v);
+        }
+    }
+
+    // Scans gray blocks until about `budget` words worth of blocks have been scanned or there's
+    // none left. Returns the number of words scanned
+    pub fn mark_slice(&mut self, budget: usize) -> usize {
+        let mut work_done = 0;
+        while work_done < budget {
+            match self.pop_gray() {
+                Some(v) => work_done += self.scan(v),
+                None => break,
+            }
+        }
+        work_done
+    }
+}
+
+impl NfAllocator {
+    // Full, non incremental mark. Every block reachable from `roots` ends up CAML_BLACK and every
+    // other allocated block CAML_WHITE, which is what nf_sweep expects. The data of the ephemerons
+    // in `weak` is marked while their key is reachable.
+    pub fn mark(&mut self, roots: &Roots, weak: &WeakRefs) {
+        let mut marker = Marker::new(self);
+        marker.mark_roots(roots);
+        loop {
+            marker.drain();
+            if !marker.mark_ephemerons(weak) {
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,269 @@
+use crate::{
+    colors::{CAML_BLACK, CAML_WHITE},
+    header::Header,
+    tags::{is_long, INFIX_TAG},
+    utils::{field_ref_mut, get_pool_layout, WORD_SIZE},
+    value::Value,
+    word::Wsize,
+};
+
+use super::{mark::first_scanned_field, roots::Roots, weak::WeakRefs};
+
+// Bigger blocks go straight to the major heap, like OCaml's Max_young_wosize
+pub const MAX_YOUNG_WOSIZE: usize = 256;
+
+// A bump pointer heap in front of the NfAllocator, like OCaml's minor heap. Blocks are allocated
+// from the end of it downwards, and a minor collection copies the ones reachable from the roots
+// and the remembered set into the major heap, after which the whole minor heap is free again.
+//
+// A promoted block's header is set to a wosize of 0, which no block in the minor heap has, and its
+// first field to where it was copied.
+pub struct MinorHeap {
+    // [start, end), 0 when there's no minor heap
+    start: usize,
+    end: usize,
+    // Header of the last block allocated, blocks are at [ptr, end)
+    ptr: usize,
+    // (block, field) of the major heap fields that may point into the minor heap, like OCaml's
+    // ref_table
+    remembered: Vec<(Value, usize)>,
+    // Promoted blocks whose fields haven't been promoted yet
+    todo: Vec<Value>,
+    num_collections: usize,
+    promoted_words: usize,
+}
+
+impl MinorHeap {
+    pub const fn new() -> Self {
+        MinorHeap {
+            start: 0,
+            end: 0,
+            ptr: 0,
+            remembered: vec![],
+            todo: vec![],
+            num_collections: 0,
+            promoted_words: 0,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.start != self.end
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ptr == self.end && self.remembered.is_empty()
+    }
+
+    // 0 gets rid of the minor heap. Has to be empty, i.e right after a collection
+    pub fn set_wsz(&mut self, wsz: usize) {
+        assert!(self.is_empty(), "Resizing a minor heap that isn't empty");
+        if self.is_enabled() {
+            let layout = get_pool_layout(Wsize::new((self.end - self.start) / WORD_SIZE));
+            unsafe { std::alloc::dealloc(self.start as *mut u8, layout) };
+            *self = MinorHeap {
+                num_collections: self.num_collections,
+                promoted_words: self.promoted_words,
+                ..MinorHeap::new()
+            };
+        }
+        if wsz == 0 {
+            return;
+        }
+
+        // Any block that's allowed in has to fit once the minor heap is empty
+        let wsz = wsz.max(MAX_YOUNG_WOSIZE + 1);
+        let layout = get_pool_layout(Wsize::new(wsz));
+        let start = unsafe { std::alloc::alloc(layout) } as usize;
+        if start == 0 {
+            std::alloc::handle_alloc_error(layout);
+        }
+        self.start = start;
+        self.end = start + wsz * WORD_SIZE;
+        self.ptr = self.end;
+    }
+
+    pub fn get_wsz(&self) -> usize {
+        (self.end - self.start) / WORD_SIZE
+    }
+
+    pub fn num_collections(&self) -> usize {
+        self.num_collections
+    }
+
+    // Words, headers included, copied into the major heap by all the collections so far
+    pub fn promoted_words(&self) -> usize {
+        self.promoted_words
+    }
+
+    pub fn is_young(&self, v: Value) -> bool {
+        !is_long(v.0) && self.start < v.0 && v.0 < self.end
+    }
+
+    // Whether a block of `wo_sz` is allocated here rather than in the major heap
+    pub fn takes(&self, wo_sz: usize) -> bool {
+        self.is_enabled() && (1..=MAX_YOUNG_WOSIZE).contains(&wo_sz)
+    }
+
+    // None once the minor heap is full. The fields are zeroed, so that a collection never finds
+    // garbage in them
+    pub fn alloc(&mut self, wo_sz: usize, tag: u8) -> Option<Value> {
+        debug_assert!(self.takes(wo_sz));
+        let wh_bytes = (wo_sz + 1) * WORD_SIZE;
+        if self.ptr - self.start < wh_bytes {
+            return None;
+        }
+        self.ptr -= wh_bytes;
+        unsafe {
+            *(self.ptr as *mut Header) = Header::new(wo_sz, CAML_BLACK, tag);
+            std::ptr::write_bytes((self.ptr + WORD_SIZE) as *mut Value, 0, wo_sz);
+        }
+        Some(Value(self.ptr + WORD_SIZE))
+    }
+
+    // Has to be called when `new` is stored in `obj[field]`. The old value of the field can't be
+    // used to tell whether it's remembered already, a block fresh out of the major heap has
+    // whatever was in that memory before
+    pub fn write_barrier(&mut self, obj: Value, field: usize, new: Value) {
+        if !self.is_young(obj)
+            && self.is_young(new)
+            && self.remembered.last() != Some(&(obj, field))
+        {
+            self.remembered.push((obj, field));
+        }
+    }
+
+    pub fn num_remembered(&self) -> usize {
+        self.remembered.len()
+    }
+
+    // A major heap block freed with dealloc, its fields are no slots anymore
+    pub fn on_dealloc(&mut self, v: Value) {
+        if !self.remembered.is_empty() {
+            self.remembered.retain(|(obj, _)| *obj != v);
+        }
+    }
+
+    // Has to be called before a sweep starts, the fields of the blocks it reclaims are no slots
+    // anymore either
+    pub fn before_sweep(&mut self) {
+        self.remembered
+            .retain(|(obj, _)| obj.get_header().get_color() != CAML_WHITE);
+    }
+
+    // Where `v` is after a collection, None for a young block that didn't survive it. Values that
+    // aren't young are where they were
+    pub fn forward(&self, v: Value) -> Option<Value> {
+        if !self.is_young(v) {
+            return Some(v);
+        }
+        let hd = v.get_header();
+        if hd.get_tag() == INFIX_TAG {
+            let offset = *hd.get_wosize().get_val() * WORD_SIZE;
+            return self
+                .forward(Value(v.0 - offset))
+                .map(|closure| Value(closure.0 + offset));
+        }
+        (*hd.get_wosize().get_val() == 0).then(|| *field_ref_mut(&v, 0))
+    }
+
+    // Copies `*slot` into the major heap if it's young and wasn't copied yet, and points `slot` to
+    // the copy
+    fn oldify(&mut self, slot: &mut Value, promote: &mut impl FnMut(usize, u8) -> Value) {
+        let v = *slot;
+        if !self.is_young(v) {
+            return;
+        }
+        let hd = v.get_header();
+        if hd.get_tag() == INFIX_TAG {
+            let offset = *hd.get_wosize().get_val() * WORD_SIZE;
+            let mut closure = Value(v.0 - offset);
+            self.oldify(&mut closure, promote);
+            *slot = Value(closure.0 + offset);
+            return;
+        }
+        let wo_sz = *hd.get_wosize().get_val();
+        if wo_sz == 0 {
+            *slot = *field_ref_mut(&v, 0);
+            return;
+        }
+
+        let new = promote(wo_sz, hd.get_tag());
+        unsafe { std::ptr::copy_nonoverlapping(v.0 as *const Value, new.0 as *mut Value, wo_sz) };
+        *hd = Header::new(0, CAML_BLACK, 0);
+        *field_ref_mut(&v, 0) = new;
+        self.promoted_words += wo_sz + 1;
+        self.todo.push(new);
+        *slot = new;
+    }
+
+    fn drain(
+        &mut self,
+        promote: &mut impl FnMut(usize, u8) -> Value,
+        darken: &mut impl FnMut(Value),
+    ) {
+        while let Some(v) = self.todo.pop() {
+            for i in first_scanned_field(v)..*v.get_header().get_wosize().get_val() {
+                let field = field_ref_mut(&v, i as isize);
+                self.oldify(field, promote);
+                darken(*field);
+            }
+        }
+    }
+
+    // Promotes every young block reachable from `roots`, the remembered set or the data of an
+    // ephemeron whose key survives, and clears the weak entries to the ones that don't.
+    // `promote` has to give a major heap block of the wosize and tag it's called with. `darken` is
+    // called with every field of the promoted blocks, which the mark in progress won't scan.
+    //
+    // The young blocks are left as they are for the caller to look at with forward, until reset
+    pub fn collect(
+        &mut self,
+        roots: &Roots,
+        weak: &mut WeakRefs,
+        mut promote: impl FnMut(usize, u8) -> Value,
+        mut darken: impl FnMut(Value),
+    ) {
+        for slot in roots.slots() {
+            self.oldify(unsafe { &mut *slot }, &mut promote);
+        }
+        for (obj, field) in std::mem::take(&mut self.remembered) {
+            self.oldify(field_ref_mut(&obj, field as isize), &mut promote);
+        }
+        self.drain(&mut promote, &mut darken);
+
+        // Data promoted for one ephemeron may be what keeps the key of another alive
+        loop {
+            let mut promoted = false;
+            for (key, data) in weak.ephemerons_mut() {
+                if self.is_young(*data) && self.forward(*key).is_some() {
+                    self.oldify(data, &mut promote);
+                    promoted = true;
+                }
+            }
+            if !promoted {
+                break;
+            }
+            self.drain(&mut promote, &mut darken);
+        }
+
+        weak.minor_collected(|v| self.forward(v));
+        self.num_collections += 1;
+    }
+
+    // Frees the whole minor heap, once nothing needs forward anymore
+    pub fn reset(&mut self) {
+        self.ptr = self.end;
+    }
+}
+
+impl Default for MinorHeap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static mut MINOR_HEAP: MinorHeap = MinorHeap::new();
+
+pub fn get_minor_heap() -> &'static mut MinorHeap {
+    unsafe { &mut *std::ptr::addr_of_mut!(MINOR_HEAP) }
+}
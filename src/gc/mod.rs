@@ -0,0 +1,612 @@
+pub mod collector;
+pub mod compact;
+pub mod conservative;
+pub mod finalizer;
+pub mod mark;
+pub mod minor;
+pub mod roots;
+pub mod weak;
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::{
+        colors::{CAML_BLACK, CAML_GRAY, CAML_WHITE},
+        freelist::allocator::NfAllocator,
+        header::Header,
+        tags::{
+            make_closinfo, val_long, CLOSURE_TAG, CUSTOM_TAG, DOUBLE_ARRAY_TAG, INFIX_TAG,
+            STRING_TAG,
+        },
+        utils::{field_ref_mut, WORD_SIZE},
+        val_hp,
+        value::Value,
+        word::Wsize,
+        DEFAULT_TAG,
+    };
+
+    use super::{
+        collector::Collector,
+        finalizer::Finalizers,
+        mark::{allocated_blocks, block_containing},
+        minor::MinorHeap,
+        roots::Roots,
+        weak::WeakRefs,
+    };
+
+    fn alloc_zeroed(allocator: &mut NfAllocator, wo_sz: usize) -> Value {
+        alloc_tagged_zeroed(allocator, wo_sz, DEFAULT_TAG)
+    }
+
+    fn alloc_tagged_zeroed(allocator: &mut NfAllocator, wo_sz: usize, tag: u8) -> Value {
+        let val = val_hp!(allocator.nf_allocate_tagged(Wsize::new(wo_sz), tag));
+        for i in 0..wo_sz {
+            *field_ref_mut(&val, i as isize) = Value(0);
+        }
+        val
+    }
+
+    #[test]
+    fn mark_test() {
+        let mut allocator = NfAllocator::new();
+        allocator.nf_expand_heap(Wsize::new(10));
+
+        // a -> b -> c, c -> a, d is garbage
+        let a = alloc_zeroed(&mut allocator, 2);
+        let b = alloc_zeroed(&mut allocator, 3);
+        let c = alloc_zeroed(&mut allocator, 1);
+        let d = alloc_zeroed(&mut allocator, 4);
+        *field_ref_mut(&a, 1) = b;
+        *field_ref_mut(&b, 2) = c;
+        *field_ref_mut(&c, 0) = a;
+        // Words that only look like pointers into a block aren't followed
+        *field_ref_mut(&b, 0) = Value(d.0 + 8);
+
+        let mut roots = Roots::new();
+        let mut global = a;
+        roots.register_global(&mut global);
+
+        allocator.mark(&roots, &WeakRefs::new());
+        for v in [a, b, c] {
+            assert_eq!(v.get_header().get_color(), CAML_BLACK);
+        }
+        assert_eq!(d.get_header().get_color(), CAML_WHITE);
+
+        let free_before = allocator.quick_stat().free_words;
+        assert_eq!(allocator.nf_sweep(), Wsize::new(5));
+        assert_eq!(allocator.quick_stat().free_words, free_before + 5);
+        // d got merged into the free block right before it
+        assert_eq!(allocator.heap_stats().live_blocks, 3);
+        for v in [a, b, c] {
+            assert_eq!(v.get_header().get_color(), CAML_WHITE);
+        }
+
+        // Only reachable through a local root now
+        roots.remove_global(&mut global);
+        let mut local = c;
+        roots.push_local(&mut local);
+        *field_ref_mut(&c, 0) = Value(0);
+        allocator.mark(&roots, &WeakRefs::new());
+        assert_eq!(c.get_header().get_color(), CAML_BLACK);
+        assert_eq!(a.get_header().get_color(), CAML_WHITE);
+        assert_eq!(b.get_header().get_color(), CAML_WHITE);
+
+        roots.pop_locals(1);
+        assert_eq!(roots.num_locals(), 0);
+        allocator.nf_sweep();
+        allocator.mark(&roots, &WeakRefs::new());
+        allocator.nf_sweep();
+        assert_eq!(allocator.heap_stats().live_blocks, 0);
+        assert!(allocator.heap_verify().is_ok());
+    }
+
+    #[test]
+    fn tagged_mark_test() {
+        let mut allocator = NfAllocator::new();
+        allocator.nf_expand_heap(Wsize::new(10));
+
+        let hidden_in_string = alloc_zeroed(&mut allocator, 1);
+        let hidden_in_doubles = alloc_zeroed(&mut allocator, 1);
+        let env = alloc_zeroed(&mut allocator, 1);
+        let code = alloc_zeroed(&mut allocator, 1);
+
+        let string = alloc_tagged_zeroed(&mut allocator, 2, STRING_TAG);
+        *field_ref_mut(&string, 1) = hidden_in_string;
+        let doubles = alloc_tagged_zeroed(&mut allocator, 1, DOUBLE_ARRAY_TAG);
+        *field_ref_mut(&doubles, 0) = hidden_in_doubles;
+
+        // Two mutually recursive functions: [code, closinfo, infix hd, code, closinfo, env]
+        let closure = alloc_tagged_zeroed(&mut allocator, 6, CLOSURE_TAG);
+        *field_ref_mut(&closure, 0) = code;
+        *field_ref_mut(&closure, 1) = Value(make_closinfo(1, 5));
+        let infix = Value(closure.0 + 3 * WORD_SIZE);
+        *infix.get_header() = Header::new(3, CAML_WHITE, INFIX_TAG);
+        *field_ref_mut(&closure, 3) = code;
+        *field_ref_mut(&closure, 4) = Value(make_closinfo(1, 2));
+        *field_ref_mut(&closure, 5) = env;
+
+        let root_block = alloc_zeroed(&mut allocator, 4);
+        *field_ref_mut(&root_block, 0) = string;
+        *field_ref_mut(&root_block, 1) = doubles;
+        // Only reachable through the pointer to its second function
+        *field_ref_mut(&root_block, 2) = infix;
+        // An int that happens to be the address of a block, plus the tag bit
+        *field_ref_mut(&root_block, 3) = Value(val_long((hidden_in_string.0 >> 1) as isize));
+
+        let mut roots = Roots::new();
+        let mut global = root_block;
+        roots.register_global(&mut global);
+        allocator.mark(&roots, &WeakRefs::new());
+
+        for v in [root_block, string, doubles, closure, env] {
+            assert_eq!(v.get_header().get_color(), CAML_BLACK);
+        }
+        // Not scanned: raw data, code pointers and immediates
+        for v in [hidden_in_string, hidden_in_doubles, code] {
+            assert_eq!(v.get_header().get_color(), CAML_WHITE);
+        }
+        assert_eq!(closure.get_header().get_tag(), CLOSURE_TAG);
+    }
+
+    #[test]
+    fn incremental_mark_test() {
+        let mut allocator = NfAllocator::new();
+        allocator.nf_expand_heap(Wsize::new(10));
+
+        // a -> b -> {x, c}, y is garbage for now
+        let a = alloc_zeroed(&mut allocator, 1);
+        let b = alloc_zeroed(&mut allocator, 2);
+        let c = alloc_zeroed(&mut allocator, 1);
+        let x = alloc_zeroed(&mut allocator, 1);
+        let y = alloc_zeroed(&mut allocator, 1);
+        let z = alloc_zeroed(&mut allocator, 1);
+        *field_ref_mut(&a, 0) = b;
+        *field_ref_mut(&b, 0) = x;
+        *field_ref_mut(&b, 1) = c;
+
+        let mut roots = Roots::new();
+        let mut global = a;
+        let slot: *mut Value = &mut global;
+        roots.register_global(slot);
+
+        let mut collector = Collector::new();
+        assert!(!collector.mark_slice(&allocator, &roots, &WeakRefs::new(), 1));
+        assert!(collector.is_marking());
+        assert_eq!(a.get_header().get_color(), CAML_BLACK);
+        assert_eq!(b.get_header().get_color(), CAML_GRAY);
+
+        // Move x from the gray b to the black a, only the barrier keeps it alive
+        collector.write_barrier(a, 0, x);
+        collector.write_barrier(b, 0, Value(0));
+        // c gets freed before b is scanned
+        collector.write_barrier(b, 1, Value(0));
+        collector.on_dealloc(c);
+        allocator.nf_deallocate(c);
+        // Freed while on the gray stack, it must not be scanned
+        collector.darken(z);
+        assert_eq!(z.get_header().get_color(), CAML_GRAY);
+        collector.on_dealloc(z);
+        allocator.nf_deallocate(z);
+        // Allocated during the mark, black already
+        let n = alloc_zeroed(&mut allocator, 1);
+        collector.write_barrier(n, 0, a);
+        // Roots aren't behind the barrier, the rescan at the end of the mark finds y
+        unsafe { *slot = y };
+
+        while !collector.mark_slice(&allocator, &roots, &WeakRefs::new(), 1) {}
+        assert!(!collector.is_marking());
+        for v in [a, b, x, y, n] {
+            assert_eq!(v.get_header().get_color(), CAML_BLACK);
+        }
+
+        allocator.nf_sweep();
+        assert_eq!(allocator.heap_stats().live_blocks, 5);
+        assert!(allocator.heap_verify().is_ok());
+    }
+
+    #[test]
+    fn space_overhead_test() {
+        let mut allocator = NfAllocator::new();
+        allocator.nf_expand_heap(Wsize::new(10));
+        let pool_wsz = *allocator.get_heap_wsz().get_val();
+
+        let mut collector = Collector::new();
+        assert!(!collector.should_start_cycle(&allocator));
+        collector.set_space_overhead(50);
+        collector.on_sweep_done(&allocator);
+
+        // Up to half a pool can be allocated before a cycle is due, the heap is less than a pool
+        let mut allocated = 0;
+        while !collector.should_start_cycle(&allocator) {
+            alloc_zeroed(&mut allocator, 99);
+            allocated += 100;
+        }
+        assert!(allocated >= pool_wsz / 2 && allocated < pool_wsz / 2 + 100);
+
+        allocator.nf_start_sweep();
+        assert!(!collector.should_start_cycle(&allocator));
+        allocator.nf_sweep();
+        collector.on_sweep_done(&allocator);
+        assert!(!collector.should_start_cycle(&allocator));
+    }
+
+    #[test]
+    fn compact_test() {
+        let mut allocator = NfAllocator::new();
+        for _ in 0..3 {
+            allocator.nf_expand_heap(Wsize::new(10));
+        }
+
+        let mut vals = vec![];
+        loop {
+            let hp = allocator.nf_allocate(Wsize::new(1000));
+            if hp.is_null() {
+                break;
+            }
+            let val = val_hp!(hp);
+            *field_ref_mut(&val, 0) = Value(0);
+            vals.push(val);
+        }
+
+        // Every 10th block is kept alive through a chain starting at a root, in allocation order
+        let kept = vals.iter().step_by(10).copied().collect::<Vec<Value>>();
+        for (i, w) in kept.windows(2).enumerate() {
+            *field_ref_mut(&w[0], 0) = w[1];
+            *field_ref_mut(&w[0], 1) = Value(val_long(i as isize));
+        }
+        *field_ref_mut(kept.last().unwrap(), 1) = Value(val_long(kept.len() as isize - 1));
+
+        // And a closure, pointed to only through its infix block
+        let closure = alloc_tagged_zeroed(&mut allocator, 4, CLOSURE_TAG);
+        *field_ref_mut(&closure, 1) = Value(make_closinfo(1, 4));
+        let infix = Value(closure.0 + 3 * WORD_SIZE);
+        *infix.get_header() = Header::new(3, CAML_WHITE, INFIX_TAG);
+        *field_ref_mut(kept.last().unwrap(), 2) = infix;
+
+        let mut roots = Roots::new();
+        let mut global = kept[0];
+        let slot: *mut Value = &mut global;
+        roots.register_global(slot);
+        allocator.mark(&roots, &WeakRefs::new());
+        allocator.nf_sweep();
+        assert_eq!(allocator.heap_stats().live_blocks, kept.len() + 1);
+
+        let top_heap_words = allocator.quick_stat().top_heap_words;
+        let forwarding = allocator.compact(&roots);
+        assert_eq!(forwarding.pools_freed, 2);
+        assert_eq!(allocator.get_num_pools(), 1);
+        assert_eq!(allocator.quick_stat().heap_expansions, 3);
+        assert!(forwarding.num_moved() > 0);
+        let report = allocator.heap_verify();
+        assert!(report.is_ok(), "{report:?}");
+        assert_eq!(report.pools, 1);
+        assert_eq!(report.free_blocks, 1);
+        assert_eq!(allocator.heap_stats().live_blocks, kept.len() + 1);
+
+        let mut cur = unsafe { *slot };
+        assert_eq!(Some(cur), forwarding.forward(kept[0]));
+        for i in 0..kept.len() {
+            assert_eq!(field_ref_mut(&cur, 1).0, val_long(i as isize));
+            if i + 1 < kept.len() {
+                cur = *field_ref_mut(&cur, 0);
+            }
+        }
+        let new_infix = *field_ref_mut(&cur, 2);
+        let new_closure = forwarding.forward(closure).unwrap();
+        assert_eq!(new_infix.0, new_closure.0 + 3 * WORD_SIZE);
+        assert_eq!(new_infix.get_header().get_tag(), INFIX_TAG);
+        assert_eq!(new_closure.get_header().get_tag(), CLOSURE_TAG);
+
+        // The heap is still usable
+        let val = alloc_zeroed(&mut allocator, 1000);
+        *field_ref_mut(&cur, 0) = val;
+        allocator.mark(&roots, &WeakRefs::new());
+        assert_eq!(val.get_header().get_color(), CAML_BLACK);
+        allocator.nf_sweep();
+        assert!(allocator.heap_verify().is_ok());
+
+        // Growing back to less than it was before the compaction
+        allocator.nf_expand_heap(Wsize::new(10));
+        assert_eq!(allocator.quick_stat().heap_expansions, 4);
+        assert_eq!(allocator.quick_stat().top_heap_words, top_heap_words);
+        let report = allocator.heap_verify();
+        assert!(report.is_ok(), "{report:?}");
+    }
+
+    static FINALIZED_FD: AtomicUsize = AtomicUsize::new(0);
+    static FINALIZED_LAST: AtomicUsize = AtomicUsize::new(0);
+
+    extern "C" fn close_fd(ptr: *mut u8) {
+        FINALIZED_FD.store(field_ref_mut(&Value(ptr as usize), 0).0, Ordering::SeqCst);
+    }
+
+    extern "C" fn count_last(wo_sz: usize, tag: u8) {
+        assert_eq!((wo_sz, tag), (2, CUSTOM_TAG));
+        FINALIZED_LAST.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn finalizer_test() {
+        let mut allocator = NfAllocator::new();
+        allocator.nf_expand_heap(Wsize::new(10));
+        let mut finalizers = Finalizers::new();
+
+        let live = alloc_tagged_zeroed(&mut allocator, 2, CUSTOM_TAG);
+        let dead_first = alloc_tagged_zeroed(&mut allocator, 2, CUSTOM_TAG);
+        *field_ref_mut(&dead_first, 0) = Value(val_long(42));
+        let dead_last = alloc_tagged_zeroed(&mut allocator, 2, CUSTOM_TAG);
+        let freed = alloc_tagged_zeroed(&mut allocator, 2, CUSTOM_TAG);
+        finalizers.register_first(live, close_fd);
+        finalizers.register_first(dead_first, close_fd);
+        finalizers.register_last(dead_last, count_last);
+        finalizers.register_last(freed, count_last);
+
+        finalizers.on_dealloc(freed);
+        allocator.nf_deallocate(freed);
+
+        let mut roots = Roots::new();
+        let mut global = live;
+        roots.register_global(&mut global);
+        allocator.mark(&roots, &WeakRefs::new());
+
+        finalizers.before_sweep();
+        assert_eq!(FINALIZED_FD.load(Ordering::SeqCst), val_long(42));
+        assert_eq!(FINALIZED_LAST.load(Ordering::SeqCst), 0);
+        allocator.nf_sweep();
+        finalizers.after_sweep();
+        assert_eq!(FINALIZED_LAST.load(Ordering::SeqCst), 1);
+        assert_eq!(finalizers.num_registered(), 1);
+
+        // Nothing left to run on the next cycle while `live` stays reachable
+        allocator.mark(&roots, &WeakRefs::new());
+        finalizers.before_sweep();
+        allocator.nf_sweep();
+        finalizers.after_sweep();
+        assert_eq!(FINALIZED_LAST.load(Ordering::SeqCst), 1);
+        assert_eq!(finalizers.num_registered(), 1);
+    }
+
+    static FINALIZED_CHILD_WOSZ: AtomicUsize = AtomicUsize::new(0);
+
+    extern "C" fn child_wosize(ptr: *mut u8) {
+        let child = *field_ref_mut(&Value(ptr as usize), 0);
+        FINALIZED_CHILD_WOSZ.store(*child.get_header().get_wosize().get_val(), Ordering::SeqCst);
+    }
+
+    #[test]
+    fn weak_test() {
+        let mut allocator = NfAllocator::new();
+        allocator.nf_expand_heap(Wsize::new(10));
+        let mut weak = WeakRefs::new();
+
+        let live = alloc_zeroed(&mut allocator, 1);
+        let dead = alloc_zeroed(&mut allocator, 1);
+        let table = weak.create_table(3);
+        assert!(weak.set(table, 0, live));
+        assert!(weak.set(table, 1, dead));
+        assert!(!weak.set(table, 3, live));
+
+        // key1 is reachable, so data1 is too even though only the ephemeron points to it. data1
+        // makes key2 reachable in turn. key3 isn't, and data3 only points to it, which mustn't keep
+        // it alive
+        let key1 = alloc_zeroed(&mut allocator, 1);
+        let data1 = alloc_zeroed(&mut allocator, 1);
+        let key2 = alloc_zeroed(&mut allocator, 1);
+        let data2 = alloc_zeroed(&mut allocator, 1);
+        let key3 = alloc_zeroed(&mut allocator, 1);
+        let data3 = alloc_zeroed(&mut allocator, 1);
+        *field_ref_mut(&data1, 0) = key2;
+        *field_ref_mut(&data3, 0) = key3;
+        // Created in the order that needs the most passes
+        let e2 = weak.create_ephemeron(key2, data2);
+        let e1 = weak.create_ephemeron(key1, data1);
+        let e3 = weak.create_ephemeron(key3, data3);
+
+        let mut roots = Roots::new();
+        let mut globals = [live, key1];
+        for global in globals.iter_mut() {
+            roots.register_global(global);
+        }
+        let mut collector = Collector::new();
+        collector.finish_marking(&allocator, &roots, &weak);
+        for v in [live, key1, data1, key2, data2] {
+            assert_eq!(v.get_header().get_color(), CAML_BLACK);
+        }
+        for v in [dead, key3, data3] {
+            assert_eq!(v.get_header().get_color(), CAML_WHITE);
+        }
+
+        weak.before_sweep();
+        allocator.nf_sweep();
+        assert_eq!(allocator.heap_stats().live_blocks, 5);
+        assert_eq!(weak.get(table, 0), live);
+        assert_eq!(weak.get(table, 1), Value(0));
+        assert_eq!(weak.get_ephemeron(e1), (key1, data1));
+        assert_eq!(weak.get_ephemeron(e2), (key2, data2));
+        assert_eq!(weak.get_ephemeron(e3), (Value(0), Value(0)));
+
+        // Freeing a block clears what points to it
+        weak.on_dealloc(live);
+        allocator.nf_deallocate(live);
+        assert_eq!(weak.get(table, 0), Value(0));
+
+        weak.free_table(table);
+        assert_eq!(weak.table_len(table), 0);
+        assert_eq!(weak.create_table(1), table);
+
+        // The full mark follows ephemerons too
+        let data4 = alloc_zeroed(&mut allocator, 1);
+        let e4 = weak.create_ephemeron(key1, data4);
+        allocator.mark(&roots, &weak);
+        assert_eq!(data4.get_header().get_color(), CAML_BLACK);
+
+        // Data the mark didn't know about is cleared even though the key is alive
+        allocator.mark(&roots, &WeakRefs::new());
+        assert_eq!(data4.get_header().get_color(), CAML_WHITE);
+        weak.before_sweep();
+        allocator.nf_sweep();
+        assert_eq!(weak.get_ephemeron(e4), (key1, Value(0)));
+        weak.free_ephemeron(e4);
+    }
+
+    #[test]
+    fn minor_test() {
+        let mut allocator = NfAllocator::new();
+        allocator.nf_expand_heap(Wsize::new(10));
+        let mut weak = WeakRefs::new();
+        let mut minor = MinorHeap::new();
+        minor.set_wsz(1024);
+        assert!(!minor.takes(0));
+        assert!(!minor.takes(1000));
+
+        // root -> a -> b, old -> d, the ephemeron keeps e alive through b, c is garbage
+        let a = minor.alloc(2, DEFAULT_TAG).unwrap();
+        let b = minor.alloc(1, DEFAULT_TAG).unwrap();
+        let c = minor.alloc(1, DEFAULT_TAG).unwrap();
+        let d = minor.alloc(1, STRING_TAG).unwrap();
+        let e = minor.alloc(1, DEFAULT_TAG).unwrap();
+        *field_ref_mut(&a, 0) = b;
+        *field_ref_mut(&a, 1) = Value(val_long(5));
+        *field_ref_mut(&d, 0) = Value(42);
+        // Fresh out of the major heap, its field happens to look like it already points to d
+        let old = allocator.nf_allocate(Wsize::new(1));
+        let old = val_hp!(old);
+        *field_ref_mut(&old, 0) = d;
+        minor.write_barrier(old, 0, d);
+        *field_ref_mut(&old, 0) = d;
+        minor.write_barrier(old, 0, d);
+        assert_eq!(minor.num_remembered(), 1);
+        // Its field is never looked at once it's left white for the sweep
+        let dead_old = alloc_zeroed(&mut allocator, 1);
+        minor.write_barrier(dead_old, 0, c);
+        *field_ref_mut(&dead_old, 0) = c;
+        *dead_old.get_header() = Header::new(1, CAML_WHITE, DEFAULT_TAG);
+        minor.before_sweep();
+        assert_eq!(minor.num_remembered(), 1);
+        allocator.nf_deallocate(dead_old);
+
+        let table = weak.create_table(2);
+        weak.set(table, 0, b);
+        weak.set(table, 1, c);
+        let ephemeron = weak.create_ephemeron(b, e);
+        // c dies, the finalizer must see the copy of b rather than what promoting it left behind
+        *field_ref_mut(&c, 0) = b;
+        let mut finalizers = Finalizers::new();
+        finalizers.register_first(c, child_wosize);
+
+        let mut roots = Roots::new();
+        let mut global = a;
+        roots.register_global(&mut global);
+        let mut darkened = 0;
+        minor.collect(
+            &roots,
+            &mut weak,
+            |wo_sz, tag| alloc_tagged_zeroed(&mut allocator, wo_sz, tag),
+            |_| darkened += 1,
+        );
+
+        let new_a = global;
+        let new_b = *field_ref_mut(&new_a, 0);
+        let new_d = *field_ref_mut(&old, 0);
+        for v in [new_a, new_b, new_d] {
+            assert!(!minor.is_young(v));
+            assert_eq!(v.get_header().get_color(), CAML_BLACK);
+        }
+        assert_eq!(minor.forward(a), Some(new_a));
+        assert_eq!(minor.forward(c), None);
+        assert_eq!(minor.forward(old), Some(old));
+        assert_eq!(*field_ref_mut(&new_a, 1), Value(val_long(5)));
+        // Not scanned, a string's field is only looked at as bytes
+        assert_eq!(new_d.get_header().get_tag(), STRING_TAG);
+        assert_eq!(*field_ref_mut(&new_d, 0), Value(42));
+        assert_eq!(weak.get(table, 0), new_b);
+        assert_eq!(weak.get(table, 1), Value(0));
+        assert_eq!(
+            weak.get_ephemeron(ephemeron),
+            (new_b, minor.forward(e).unwrap())
+        );
+        // a, b and e have 4 fields that get scanned between them
+        assert_eq!(darkened, 4);
+        assert_eq!(minor.promoted_words(), 3 + 2 + 2 + 2);
+        assert_eq!(allocator.heap_stats().live_blocks, 5);
+
+        finalizers.minor_collected(|v| minor.forward(v));
+        assert_eq!(FINALIZED_CHILD_WOSZ.load(Ordering::SeqCst), 1);
+        assert_eq!(finalizers.num_registered(), 0);
+
+        minor.reset();
+        assert!(minor.is_empty());
+        assert_eq!(minor.alloc(2, DEFAULT_TAG), Some(a));
+        minor.reset();
+        minor.set_wsz(0);
+        assert!(!minor.is_enabled());
+    }
+
+    #[test]
+    fn conservative_mark_test() {
+        let mut allocator = NfAllocator::new();
+        allocator.nf_expand_heap(Wsize::new(10));
+
+        // a is only pointed inside of from the range, b from the stack. a -> c is followed precisely
+        let a = alloc_zeroed(&mut allocator, 4);
+        let b = alloc_zeroed(&mut allocator, 2);
+        let c = alloc_zeroed(&mut allocator, 1);
+        let dead = alloc_zeroed(&mut allocator, 1);
+        *field_ref_mut(&a, 3) = c;
+        let range = [a.0 + 2 * WORD_SIZE + 3, val_long(7), dead.0 - WORD_SIZE];
+
+        let mut roots = Roots::new();
+        roots.add_range(range.as_ptr() as usize, std::mem::size_of_val(&range));
+        allocator.mark(&roots, &WeakRefs::new());
+        for v in [a, c] {
+            assert_eq!(v.get_header().get_color(), CAML_BLACK);
+        }
+        // Not kept alive by a pointer to its header
+        for v in [b, dead] {
+            assert_eq!(v.get_header().get_color(), CAML_WHITE);
+        }
+
+        roots.set_conservative(true);
+        let on_stack = std::hint::black_box(b.0 + WORD_SIZE);
+        allocator.mark(&roots, &WeakRefs::new());
+        assert_eq!(b.get_header().get_color(), CAML_BLACK);
+        std::hint::black_box(on_stack);
+
+        roots.remove_range(range.as_ptr() as usize);
+        roots.set_conservative(false);
+        allocator.mark(&roots, &WeakRefs::new());
+        for v in [a, b, c, dead] {
+            assert_eq!(v.get_header().get_color(), CAML_WHITE);
+        }
+    }
+
+    #[test]
+    fn block_containing_test() {
+        let mut allocator = NfAllocator::new();
+        allocator.nf_expand_heap(Wsize::new(10));
+        allocator.nf_expand_heap(Wsize::new(10));
+
+        // [free][c][b][a] in the first pool
+        let a = val_hp!(allocator.nf_allocate(Wsize::new(3)));
+        let b = val_hp!(allocator.nf_allocate(Wsize::new(2)));
+        let c = val_hp!(allocator.nf_allocate(Wsize::new(1)));
+        let word = std::mem::size_of::<usize>();
+        let blocks = allocated_blocks(&allocator);
+
+        assert_eq!(block_containing(&blocks, a.0), Some(a));
+        assert_eq!(block_containing(&blocks, a.0 + 2 * word + 1), Some(a));
+        assert_eq!(block_containing(&blocks, b.0 + word), Some(b));
+        assert_eq!(block_containing(&blocks, c.0), Some(c));
+        // Headers, past the end of the pool, free memory and outside of the heap
+        assert_eq!(block_containing(&blocks, a.0 - word), None);
+        assert_eq!(block_containing(&blocks, a.0 + 3 * word), None);
+        assert_eq!(block_containing(&blocks, c.0 - 2 * word), None);
+        assert_eq!(block_containing(&blocks, 0x10), None);
+
+        allocator.nf_deallocate(b);
+        let blocks = allocated_blocks(&allocator);
+        assert_eq!(block_containing(&blocks, b.0), None);
+        assert_eq!(block_containing(&blocks, a.0 + word), Some(a));
+    }
+}
@@ -0,0 +1,99 @@
+use crate::value::Value;
+
+use super::conservative::{scan_range, scan_stack};
+
+// Root slots, i.e addresses of variables holding a pointer returned by alloc(or anything else,
+// slots that don't point to a block are ignored). Like OCaml's caml_register_global_root and
+// CAMLlocal, the slots are read when marking, so the variables can be changed freely after
+// being registered.
+pub struct Roots {
+    globals: Vec<*mut Value>,
+    // Used like a stack, pushed on entering a function and popped before leaving it
+    locals: Vec<*mut Value>,
+    // (start, len in bytes) of memory where any word pointing inside a block keeps it alive
+    ranges: Vec<(usize, usize)>,
+    // Whether the stack and registers of the thread marking are scanned like ranges
+    conservative: bool,
+}
+
+impl Roots {
+    pub const fn new() -> Self {
+        Roots {
+            globals: vec![],
+            locals: vec![],
+            ranges: vec![],
+            conservative: false,
+        }
+    }
+
+    pub fn register_global(&mut self, slot: *mut Value) {
+        if !self.globals.contains(&slot) {
+            self.globals.push(slot);
+        }
+    }
+
+    pub fn remove_global(&mut self, slot: *mut Value) {
+        self.globals.retain(|s| *s != slot);
+    }
+
+    pub fn push_local(&mut self, slot: *mut Value) {
+        self.locals.push(slot);
+    }
+
+    pub fn pop_locals(&mut self, n: usize) {
+        let len = self.locals.len();
+        self.locals.truncate(len.saturating_sub(n));
+    }
+
+    pub fn num_locals(&self) -> usize {
+        self.locals.len()
+    }
+
+    pub fn add_range(&mut self, start: usize, len: usize) {
+        self.ranges.push((start, len));
+    }
+
+    pub fn remove_range(&mut self, start: usize) {
+        self.ranges.retain(|(s, _)| *s != start);
+    }
+
+    pub fn set_conservative(&mut self, conservative: bool) {
+        self.conservative = conservative;
+    }
+
+    pub fn is_conservative(&self) -> bool {
+        self.conservative
+    }
+
+    // Blocks that ambiguous roots point to can't be moved, since the words can't be rewritten
+    pub fn has_ambiguous(&self) -> bool {
+        self.conservative || !self.ranges.is_empty()
+    }
+
+    // Calls `f` with every word of the ranges, and of the stack and registers in conservative mode.
+    // Each of them may or may not be a pointer
+    pub fn scan_ambiguous(&self, mut f: impl FnMut(usize)) {
+        for (start, len) in self.ranges.iter() {
+            scan_range(*start, *len, &mut f);
+        }
+        if self.conservative {
+            scan_stack(f);
+        }
+    }
+
+    pub fn slots(&self) -> impl Iterator<Item = *mut Value> + '_ {
+        self.globals.iter().chain(self.locals.iter()).copied()
+    }
+}
+
+impl Default for Roots {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static mut ROOTS: Roots = Roots::new();
+
+pub fn get_roots() -> &'static mut Roots {
+    unsafe { &mut *std::ptr::addr_of_mut!(ROOTS) }
+}
@@ -0,0 +1,228 @@
+use std::collections::BTreeMap;
+
+use crate::{colors::CAML_WHITE, value::Value};
+
+// Where a weak table or an ephemeron is in WeakRefs
+pub type WeakHandle = usize;
+
+// Weak pointer tables and ephemerons, like OCaml's Weak and Ephemeron.K1. They live outside of the
+// heap, so the mark phase never scans them. Once a mark is over the entries whose target is still
+// CAML_WHITE are cleared by before_sweep, before the sweep reclaims the target. Entries must be
+// pointers returned by alloc, or NULL.
+pub struct WeakRefs {
+    tables: Vec<Option<Vec<Value>>>,
+    // (key, data). data is kept alive by the mark phase only while key is reachable
+    ephemerons: Vec<Option<(Value, Value)>>,
+    // Number of entries pointing to each block, so that freeing one nothing points to is cheap
+    targets: BTreeMap<usize, usize>,
+}
+
+fn insert<T>(entries: &mut Vec<Option<T>>, entry: T) -> WeakHandle {
+    match entries.iter().position(|e| e.is_none()) {
+        Some(handle) => {
+            entries[handle] = Some(entry);
+            handle
+        }
+        None => {
+            entries.push(Some(entry));
+            entries.len() - 1
+        }
+    }
+}
+
+fn add_target(targets: &mut BTreeMap<usize, usize>, val: Value) {
+    if val.0 != 0 {
+        *targets.entry(val.0).or_insert(0) += 1;
+    }
+}
+
+fn remove_target(targets: &mut BTreeMap<usize, usize>, val: Value) {
+    if let Some(count) = targets.get_mut(&val.0) {
+        *count -= 1;
+        if *count == 0 {
+            targets.remove(&val.0);
+        }
+    }
+}
+
+fn is_dead(val: Value) -> bool {
+    val.0 != 0 && val.get_header().get_color() == CAML_WHITE
+}
+
+impl WeakRefs {
+    pub const fn new() -> Self {
+        WeakRefs {
+            tables: vec![],
+            ephemerons: vec![],
+            targets: BTreeMap::new(),
+        }
+    }
+
+    pub fn create_table(&mut self, len: usize) -> WeakHandle {
+        insert(&mut self.tables, vec![Value(0); len])
+    }
+
+    pub fn free_table(&mut self, handle: WeakHandle) {
+        if let Some(table) = self.tables.get_mut(handle).and_then(|t| t.take()) {
+            for val in table {
+                remove_target(&mut self.targets, val);
+            }
+        }
+    }
+
+    fn table_slot(&mut self, handle: WeakHandle, i: usize) -> Option<&mut Value> {
+        self.tables.get_mut(handle)?.as_mut()?.get_mut(i)
+    }
+
+    // Returns false if there's no such table or slot
+    pub fn set(&mut self, handle: WeakHandle, i: usize, val: Value) -> bool {
+        let Some(slot) = self.table_slot(handle, i) else {
+            return false;
+        };
+        let old = std::mem::replace(slot, val);
+        remove_target(&mut self.targets, old);
+        add_target(&mut self.targets, val);
+        true
+    }
+
+    // NULL for an empty or cleared slot, and for one that doesn't exist
+    pub fn get(&mut self, handle: WeakHandle, i: usize) -> Value {
+        self.table_slot(handle, i).map_or(Value(0), |slot| *slot)
+    }
+
+    pub fn table_len(&self, handle: WeakHandle) -> usize {
+        self.tables
+            .get(handle)
+            .and_then(|t| t.as_ref())
+            .map_or(0, |t| t.len())
+    }
+
+    pub fn create_ephemeron(&mut self, key: Value, data: Value) -> WeakHandle {
+        add_target(&mut self.targets, key);
+        add_target(&mut self.targets, data);
+        insert(&mut self.ephemerons, (key, data))
+    }
+
+    pub fn free_ephemeron(&mut self, handle: WeakHandle) {
+        if let Some((key, data)) = self.ephemerons.get_mut(handle).and_then(|e| e.take()) {
+            remove_target(&mut self.targets, key);
+            remove_target(&mut self.targets, data);
+        }
+    }
+
+    // (key, data), both NULL once the key was found unreachable
+    pub fn get_ephemeron(&self, handle: WeakHandle) -> (Value, Value) {
+        self.ephemerons
+            .get(handle)
+            .and_then(|e| *e)
+            .unwrap_or((Value(0), Value(0)))
+    }
+
+    pub fn ephemerons(&self) -> impl Iterator<Item = (Value, Value)> + '_ {
+        self.ephemerons.iter().flatten().copied()
+    }
+
+    // Anything written through these has to be followed by minor_collected or relocate, which
+    // recount the targets
+    pub fn ephemerons_mut(&mut self) -> impl Iterator<Item = (&mut Value, &mut Value)> {
+        self.ephemerons
+            .iter_mut()
+            .flatten()
+            .map(|(key, data)| (key, data))
+    }
+
+    fn entries_mut(&mut self) -> impl Iterator<Item = &mut Value> {
+        self.tables
+            .iter_mut()
+            .flatten()
+            .flat_map(|t| t.iter_mut())
+            .chain(
+                self.ephemerons
+                    .iter_mut()
+                    .flatten()
+                    .flat_map(|(key, data)| [key, data]),
+            )
+    }
+
+    fn recount_targets(&mut self) {
+        let mut targets = BTreeMap::new();
+        for val in self.entries_mut() {
+            add_target(&mut targets, *val);
+        }
+        self.targets = targets;
+    }
+
+    // Has to be called once the mark is over and before the sweep starts. The data of an ephemeron
+    // whose key is alive is only dead if the mark didn't know about the ephemeron, it's cleared
+    // all the same rather than left dangling
+    pub fn before_sweep(&mut self) {
+        for slot in self.tables.iter_mut().flatten().flat_map(|t| t.iter_mut()) {
+            if is_dead(*slot) {
+                *slot = Value(0);
+            }
+        }
+        for (key, data) in self.ephemerons.iter_mut().flatten() {
+            if is_dead(*key) {
+                *key = Value(0);
+                *data = Value(0);
+            } else if is_dead(*data) {
+                *data = Value(0);
+            }
+        }
+        self.recount_targets();
+    }
+
+    // The address of a block freed with dealloc may be handed out again
+    pub fn on_dealloc(&mut self, val: Value) {
+        if !self.targets.contains_key(&val.0) {
+            return;
+        }
+        for (key, data) in self.ephemerons.iter_mut().flatten() {
+            if *key == val {
+                *key = Value(0);
+                *data = Value(0);
+            }
+        }
+        for slot in self.entries_mut() {
+            if *slot == val {
+                *slot = Value(0);
+            }
+        }
+        self.recount_targets();
+    }
+
+    // After a minor collection, `forward` gives the new address of a promoted block and None for
+    // a young block that wasn't promoted
+    pub fn minor_collected(&mut self, forward: impl Fn(Value) -> Option<Value>) {
+        for slot in self.tables.iter_mut().flatten().flat_map(|t| t.iter_mut()) {
+            *slot = forward(*slot).unwrap_or(Value(0));
+        }
+        for (key, data) in self.ephemerons.iter_mut().flatten() {
+            (*key, *data) = match forward(*key) {
+                Some(key) => (key, forward(*data).unwrap_or(Value(0))),
+                None => (Value(0), Value(0)),
+            };
+        }
+        self.recount_targets();
+    }
+
+    // Blocks moved by a compaction, `forward` gives the new address of a block
+    pub fn relocate(&mut self, forward: impl Fn(Value) -> Option<Value>) {
+        for slot in self.entries_mut() {
+            *slot = forward(*slot).unwrap_or(*slot);
+        }
+        self.recount_targets();
+    }
+}
+
+impl Default for WeakRefs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static mut WEAK_REFS: WeakRefs = WeakRefs::new();
+
+pub fn get_weak_refs() -> &'static mut WeakRefs {
+    unsafe { &mut *std::ptr::addr_of_mut!(WEAK_REFS) }
+}
@@ -1,7 +1,7 @@
 use std::fmt::{self, Debug};
 
 use crate::{
-    colors::{Color, CAML_BLACK, CAML_BLUE, CAML_GRAY, CAML_WHITE},
+    colors::{color_name, Color},
     word::Wsize,
 };
 
@@ -28,15 +28,7 @@ impl Debug for Header {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Header")
             .field("size", &self.get_wosize())
-            .field("color", {
-                match self.get_color() {
-                    CAML_BLUE => &"Blue",
-                    CAML_GRAY => &"Gray",
-                    CAML_BLACK => &"Black",
-                    CAML_WHITE => &"White",
-                    _ => &"Unknown",
-                }
-            })
+            .field("color", &color_name(self.get_color()))
             .field("tag", &self.get_tag())
             .finish()
     }
@@ -1,14 +1,42 @@
-#![allow(clippy::mut_from_ref)]
+#![allow(
+    clippy::mut_from_ref,
+    clippy::macro_metavars_in_unsafe,
+    clippy::missing_safety_doc
+)]
+pub mod callbacks;
 mod colors;
 mod freelist;
+pub mod gc;
 mod header;
+pub mod memprof;
+pub mod metrics;
+pub mod replay;
+mod tags;
+pub mod trace;
 mod utils;
 mod value;
+mod viz;
 mod word;
 
+pub use colors::{Color, CAML_BLACK, CAML_BLUE, CAML_GRAY, CAML_WHITE};
 use freelist::allocator::get_global_allocator;
+pub use freelist::{
+    compact::Forwarding,
+    leak::{LeakGroup, LeakReport},
+    stats::{FragmentationReport, HeapStats, QuickStat, HISTOGRAM_BUCKETS},
+    verify::HeapVerifyReport,
+    walker::{HeapWalker, HeapWalkerVal},
+};
+pub use gc::minor::MAX_YOUNG_WOSIZE;
+pub use header::Header;
+pub use tags::{
+    arity_closinfo, is_long, long_val, make_closinfo, start_env_closinfo, val_long, ABSTRACT_TAG,
+    CLOSURE_TAG, CUSTOM_TAG, DOUBLE_ARRAY_TAG, DOUBLE_TAG, FORWARD_TAG, INFIX_TAG, LAZY_TAG,
+    NO_SCAN_TAG, OBJECT_TAG, STRING_TAG,
+};
 use utils::field_val;
 use value::{Value, VAL_NULL};
+pub use viz::{dump_current_heap, parse_dump, render_ansi, render_svg, DumpBlock, DumpPool};
 use word::Wsize;
 
 pub const DEFAULT_COLOR: colors::Color = colors::CAML_BLUE;
@@ -19,31 +47,128 @@ static mut MEM_RANGES: Vec<(usize, usize)> = vec![];
 
 #[no_mangle]
 pub extern "C" fn alloc(wo_sz: std::ffi::c_ulonglong) -> *mut u8 {
-    let mut mem = get_global_allocator().nf_allocate(Wsize::new(wo_sz as usize));
+    alloc_tagged(wo_sz, DEFAULT_TAG)
+}
+
+// Same as alloc, but the block's header gets `tag` instead of DEFAULT_TAG
+#[no_mangle]
+pub extern "C" fn alloc_tagged(wo_sz: std::ffi::c_ulonglong, tag: u8) -> *mut u8 {
+    if gc::collector::get_collector().should_start_cycle(get_global_allocator()) {
+        start_major_cycle();
+    }
+    let sweep_work = gc::collector::get_collector().sweep_work_per_word;
+    if sweep_work != 0 {
+        sweep_slice(sweep_work.saturating_mul(wo_sz as usize + 1));
+    }
+
+    if gc::minor::get_minor_heap().takes(wo_sz as usize) {
+        if let Some(young) = gc::minor::get_minor_heap().alloc(wo_sz as usize, tag) {
+            return young.0 as *mut u8;
+        }
+        minor_collection();
+        let young = gc::minor::get_minor_heap().alloc(wo_sz as usize, tag);
+        return young.unwrap().0 as *mut u8;
+    }
+    alloc_major(wo_sz, tag)
+}
+
+// Allocates in the NfAllocator, growing the heap if it has to. Unlike alloc_tagged, never starts
+// or does any collection work besides lazy sweeping, which is what minor collections need to
+// promote blocks
+fn alloc_major(wo_sz: std::ffi::c_ulonglong, tag: u8) -> *mut u8 {
+    let mut mem = get_global_allocator().nf_allocate_tagged(Wsize::new(wo_sz as usize), tag);
+    if let Some(reclaimed) = get_global_allocator().take_lazy_sweep_done() {
+        sweep_done(reclaimed);
+    }
 
     #[cfg(feature = "check_invariants")]
     get_global_allocator().verify_nf_last_invariant();
+    // Out of memory, with the first pool being all there is. Not an allocation, nothing is told
     #[cfg(feature = "no_expand_heap")]
-    if get_global_allocator().get_num_of_expansions() == 1 {
+    if Value(mem as usize) == VAL_NULL && get_global_allocator().get_num_of_expansions() == 1 {
         return field_val(Value(mem as usize), 1).0 as *mut u8;
     }
 
     if Value(mem as usize) == VAL_NULL {
         // add new block and allocate
-        get_global_allocator().nf_expand_heap(Wsize::new(wo_sz as usize));
+        let pool = get_global_allocator().nf_expand_heap(Wsize::new(wo_sz as usize));
+        if let Some(on_expand) = callbacks::get_callbacks().on_expand {
+            on_expand(
+                pool as *mut u8,
+                *freelist::pool::Pool::get_wo_sz_from_raw(&pool).get_val(),
+            );
+        }
 
         #[cfg(debug_assertions)]
         unsafe {
-            MEM_RANGES.push(get_global_allocator().get_start_end_after_heap_expand());
+            (*std::ptr::addr_of_mut!(MEM_RANGES))
+                .push(get_global_allocator().get_start_end_after_heap_expand());
         }
 
-        mem = get_global_allocator().nf_allocate(Wsize::new(wo_sz as usize));
+        mem = get_global_allocator().nf_allocate_tagged(Wsize::new(wo_sz as usize), tag);
     }
 
     #[cfg(feature = "check_invariants")]
     get_global_allocator().verify_nf_last_invariant();
 
-    field_val(Value(mem as usize), 1).0 as *mut u8
+    let bp = field_val(Value(mem as usize), 1).0 as *mut u8;
+    #[cfg(feature = "trace")]
+    trace::record(trace::TraceOp::Alloc, wo_sz as usize, bp as usize);
+    memprof::get_memprof().on_alloc(bp as usize, wo_sz as usize);
+    if let Some(on_alloc) = callbacks::get_callbacks().on_alloc {
+        on_alloc(bp, wo_sz as usize);
+    }
+    bp
+}
+
+// Allocates a block of the wosize in `hd`(an OCaml header word, `(wosize << 10) + color + tag`)
+// and gives it that header. Returns NULL if the color is CAML_BLUE or CAML_GRAY, see
+// set_block_color
+#[no_mangle]
+pub extern "C" fn alloc_with_header(hd: usize) -> *mut u8 {
+    let color = hd & (CAML_BLACK | CAML_GRAY | CAML_BLUE | CAML_WHITE);
+    if !is_settable_color(color) {
+        return std::ptr::null_mut();
+    }
+    let bp = alloc_tagged((hd >> 10) as std::ffi::c_ulonglong, hd as u8);
+    let res = set_block_color(bp, color);
+    debug_assert_eq!(res, 0);
+    bp
+}
+
+#[no_mangle]
+pub extern "C" fn block_tag(bp: *mut u8) -> u8 {
+    Value(bp as usize).get_header().get_tag()
+}
+
+#[no_mangle]
+pub extern "C" fn set_block_tag(bp: *mut u8, tag: u8) {
+    let val = Value(bp as usize);
+    let hd = val.get_header();
+    *hd = Header::new(*hd.get_wosize().get_val(), hd.get_color(), tag);
+}
+
+#[no_mangle]
+pub extern "C" fn block_color(bp: *mut u8) -> Color {
+    Value(bp as usize).get_header().get_color()
+}
+
+// Making a block CAML_BLUE would have sweep take it for a free list entry, and a CAML_GRAY block
+// that isn't on the gray stack would never get scanned
+fn is_settable_color(color: Color) -> bool {
+    color == CAML_WHITE || color == CAML_BLACK
+}
+
+// Returns 0 on success and -1 if `color` isn't CAML_WHITE or CAML_BLACK
+#[no_mangle]
+pub extern "C" fn set_block_color(bp: *mut u8, color: Color) -> std::ffi::c_int {
+    if !is_settable_color(color) {
+        return -1;
+    }
+    let val = Value(bp as usize);
+    let hd = val.get_header();
+    *hd = Header::new(*hd.get_wosize().get_val(), color, hd.get_tag());
+    0
 }
 
 #[no_mangle]
@@ -51,47 +176,660 @@ pub extern "C" fn dealloc(bp: *mut u8) {
     let val_bp = Value(bp as usize);
     let hd_bp = field_val(Value(bp as usize), -1);
 
-    if hd_bp == VAL_NULL {
+    // Young blocks are only ever freed by minor collections
+    if hd_bp == VAL_NULL || gc::minor::get_minor_heap().is_young(val_bp) {
         return;
     }
 
     #[cfg(debug_assertions)]
     {
         let bp_as_usize = bp as usize;
-        if !unsafe { &MEM_RANGES }
+        if !unsafe { &*std::ptr::addr_of!(MEM_RANGES) }
             .iter()
             .any(|r| r.0 <= bp_as_usize && bp_as_usize <= r.1)
         {
             panic!(
                 "Invalid Memory, Got mem address: {}\n Valid memory addresses: {:?}",
                 bp_as_usize,
-                unsafe { &MEM_RANGES }
+                unsafe { &*std::ptr::addr_of!(MEM_RANGES) }
             );
         }
     }
 
+    let wo_sz = *val_bp.get_header().get_wosize().get_val();
+    gc::minor::get_minor_heap().on_dealloc(val_bp);
+    #[cfg(feature = "trace")]
+    trace::record(trace::TraceOp::Dealloc, wo_sz, bp as usize);
+    if let Some(on_free) = callbacks::get_callbacks().on_free {
+        on_free(bp, wo_sz);
+    }
+
+    memprof::get_memprof().on_dealloc(bp as usize);
+    gc::collector::get_collector().on_dealloc(val_bp);
+    gc::finalizer::get_finalizers().on_dealloc(val_bp);
+    gc::weak::get_weak_refs().on_dealloc(val_bp);
     get_global_allocator().nf_deallocate(val_bp);
 
     #[cfg(feature = "check_invariants")]
     get_global_allocator().verify_nf_last_invariant();
 }
 
+// Frees the blocks that are still CAML_WHITE. Finishes the sweep in progress if there's one, and
+// the mark in progress before that
 #[no_mangle]
 pub extern "C" fn sweep() {
-    get_global_allocator().nf_sweep();
+    // Sweeping half marked blocks would free reachable ones
+    if gc::collector::get_collector().is_marking() {
+        mark();
+    }
+    start_sweep();
+    sweep_slice(usize::MAX);
+}
+
+fn start_sweep() {
+    if !get_global_allocator().is_sweeping() {
+        gc::minor::get_minor_heap().before_sweep();
+        gc::weak::get_weak_refs().before_sweep();
+        gc::finalizer::get_finalizers().before_sweep();
+        memprof::get_memprof().before_sweep();
+        get_global_allocator().nf_start_sweep();
+    }
+}
+
+// Sweeps about `budget_words` words worth of blocks of the sweep in progress, which mark and
+// mark_slice start once they're over. Returns true once there's nothing left to sweep
+#[no_mangle]
+pub extern "C" fn sweep_slice(budget_words: usize) -> bool {
+    match get_global_allocator().nf_sweep_slice(Wsize::new(budget_words)) {
+        Some(reclaimed) => {
+            sweep_done(reclaimed);
+            true
+        }
+        None => !get_global_allocator().is_sweeping(),
+    }
+}
+
+fn sweep_done(reclaimed: Wsize) {
+    gc::collector::get_collector().on_sweep_done(get_global_allocator());
+    gc::finalizer::get_finalizers().after_sweep();
+    #[cfg(feature = "trace")]
+    {
+        let allocated = heap_walker()
+            .filter(|it| it.get_header().get_color() != colors::CAML_BLUE)
+            .map(|it| it.get_addr())
+            .collect::<std::collections::HashSet<_>>();
+        trace::forget_reclaimed(|addr| allocated.contains(&addr));
+        trace::record(trace::TraceOp::Sweep, *reclaimed.get_val(), 0);
+    }
+    if let Some(on_sweep_done) = callbacks::get_callbacks().on_sweep_done {
+        on_sweep_done(*reclaimed.get_val());
+    }
+}
+
+// With lazy sweeping on, the sweep mark and mark_slice start is done by alloc, a pool at a time
+// whenever the free list runs out, before growing the heap. sweep and sweep_slice can still be
+// used to get it over with
+#[no_mangle]
+pub extern "C" fn set_lazy_sweep(lazy: bool) {
+    get_global_allocator().set_lazy_sweep(lazy);
+}
+
+// Starts a major cycle from alloc once the words allocated since the last sweep are more than
+// `percent` percent of what was live after it. 0, the default, leaves it to the embedder. Only
+// turn it on once all the roots are registered, anything not reachable from them gets freed
+#[no_mangle]
+pub extern "C" fn set_space_overhead(percent: usize) {
+    gc::collector::get_collector().set_space_overhead(percent);
+}
+
+// A full mark, then a sweep unless lazy sweeping or allocation driven sweep slices are on, in which
+// case the sweep is left to them
+fn start_major_cycle() {
+    mark();
+    if !get_global_allocator().is_lazy_sweep()
+        && gc::collector::get_collector().sweep_work_per_word == 0
+    {
+        sweep();
+    }
+}
+
+// Words to sweep for every word allocated while a sweep is in progress, like OCaml's major slice
+// does. 0, the default, leaves all of the sweeping to sweep and sweep_slice
+#[no_mangle]
+pub extern "C" fn set_sweep_work_per_word(work: usize) {
+    gc::collector::get_collector().sweep_work_per_word = work;
+}
+
+// Marks every block reachable from the registered roots CAML_BLACK and every other allocated block
+// CAML_WHITE, then starts a sweep for the white ones. Finishes the mark in progress if mark_slice
+// started one, and the sweep in progress before starting a new mark
+#[no_mangle]
+pub extern "C" fn mark() {
+    if !gc::collector::get_collector().is_marking() {
+        sweep_slice(usize::MAX);
+    }
+    minor_collection();
+    gc::collector::get_collector().finish_marking(
+        get_global_allocator(),
+        gc::roots::get_roots(),
+        gc::weak::get_weak_refs(),
+    );
+    start_sweep();
+}
+
+// Does about `budget_words` words worth of scanning, starting a new mark if there's none in
+// progress. Returns true once the mark is over and a sweep was started. Until then, pointers must
+// only be stored in blocks through write_barrier
+#[no_mangle]
+pub extern "C" fn mark_slice(budget_words: usize) -> bool {
+    if !gc::collector::get_collector().is_marking() {
+        sweep_slice(usize::MAX);
+    }
+    // Young blocks aren't scanned by the mark, so any old block only they point to has to be found
+    // through their promotion
+    minor_collection();
+    let done = gc::collector::get_collector().mark_slice(
+        get_global_allocator(),
+        gc::roots::get_roots(),
+        gc::weak::get_weak_refs(),
+        budget_words,
+    );
+    if done {
+        start_sweep();
+    }
+    done
+}
+
+#[no_mangle]
+pub extern "C" fn is_marking() -> bool {
+    gc::collector::get_collector().is_marking()
+}
+
+// Does `obj[field] = new`, and while a mark is in progress makes sure `new` isn't collected. With a
+// minor heap, it also has to be used for every store into a block that may be young
+#[no_mangle]
+pub extern "C" fn write_barrier(obj: *mut u8, field: usize, new: *mut u8) {
+    let obj = Value(obj as usize);
+    gc::minor::get_minor_heap().write_barrier(obj, field, Value(new as usize));
+    gc::collector::get_collector().write_barrier(obj, field, Value(new as usize));
+}
+
+// `f` gets called with `ptr` right before the sweep frees it, once a mark found it unreachable.
+// It must not call into the allocator, nor keep `ptr` around
+#[no_mangle]
+pub extern "C" fn register_finalizer(ptr: *mut u8, f: gc::finalizer::FinalizerFirst) {
+    gc::finalizer::get_finalizers().register_first(Value(ptr as usize), f);
+}
+
+// Same as register_finalizer, but `f` runs after the sweep and only gets the wosize and tag that
+// the block had
+#[no_mangle]
+pub extern "C" fn register_finalizer_last(ptr: *mut u8, f: gc::finalizer::FinalizerLast) {
+    gc::finalizer::get_finalizers().register_last(Value(ptr as usize), f);
+}
+
+// A table of `len` weak pointers, all NULL. Entries are cleared once a mark finds their target
+// unreachable, they don't keep it alive. Returns the handle the other weak_ functions take
+#[no_mangle]
+pub extern "C" fn weak_create(len: usize) -> usize {
+    gc::weak::get_weak_refs().create_table(len)
+}
+
+#[no_mangle]
+pub extern "C" fn weak_free(table: usize) {
+    gc::weak::get_weak_refs().free_table(table);
+}
+
+// 0 for a table that doesn't exist
+#[no_mangle]
+pub extern "C" fn weak_length(table: usize) -> usize {
+    gc::weak::get_weak_refs().table_len(table)
+}
+
+// `ptr` must be a pointer returned by alloc, or NULL. Returns false if there's no such entry
+#[no_mangle]
+pub extern "C" fn weak_set(table: usize, i: usize, ptr: *mut u8) -> bool {
+    gc::weak::get_weak_refs().set(table, i, Value(ptr as usize))
+}
+
+// NULL if the entry was never set, or its target was collected
+#[no_mangle]
+pub extern "C" fn weak_get(table: usize, i: usize) -> *mut u8 {
+    let val = gc::weak::get_weak_refs().get(table, i);
+    gc::collector::get_collector().read_barrier(val).0 as *mut u8
+}
+
+// `data` is kept alive by marking as long as `key` is reachable, without `data` keeping `key`
+// alive. Once `key` is found unreachable both are cleared. Returns the handle the other ephemeron_
+// functions take
+#[no_mangle]
+pub extern "C" fn ephemeron_create(key: *mut u8, data: *mut u8) -> usize {
+    gc::weak::get_weak_refs().create_ephemeron(Value(key as usize), Value(data as usize))
+}
+
+#[no_mangle]
+pub extern "C" fn ephemeron_free(ephemeron: usize) {
+    gc::weak::get_weak_refs().free_ephemeron(ephemeron);
+}
+
+// NULL once the key was collected
+#[no_mangle]
+pub extern "C" fn ephemeron_get_key(ephemeron: usize) -> *mut u8 {
+    let (key, _) = gc::weak::get_weak_refs().get_ephemeron(ephemeron);
+    gc::collector::get_collector().read_barrier(key).0 as *mut u8
+}
+
+// NULL once the key was collected
+#[no_mangle]
+pub extern "C" fn ephemeron_get_data(ephemeron: usize) -> *mut u8 {
+    let (_, data) = gc::weak::get_weak_refs().get_ephemeron(ephemeron);
+    gc::collector::get_collector().read_barrier(data).0 as *mut u8
+}
+
+// Turns on a bump pointer minor heap of `words` words in front of alloc, or off with 0, after
+// emptying the current one. Blocks of up to MAX_YOUNG_WOSIZE fields get allocated there, and only
+// the ones still reachable from the roots when it fills up are moved into the major heap. Any
+// alloc may move young blocks, so every pointer held across one has to be in a registered root,
+// and stores into blocks have to go through write_barrier. Memprof, alloc callbacks and traces only
+// see blocks once they get into the major heap. Returns -1 without changing anything if words
+// isn't 0 while there are ambiguous roots(see set_conservative_mark), 0 otherwise
+#[no_mangle]
+pub extern "C" fn set_minor_heap_wsz(words: usize) -> std::ffi::c_int {
+    if words != 0 && gc::roots::get_roots().has_ambiguous() {
+        return -1;
+    }
+    minor_collection();
+    gc::minor::get_minor_heap().set_wsz(words);
+    0
+}
+
+// Moves the young blocks reachable from the roots, the fields write_barrier recorded and
+// ephemerons into the major heap, and frees the rest of the minor heap
+#[no_mangle]
+pub extern "C" fn minor_collection() {
+    let minor = gc::minor::get_minor_heap();
+    if minor.is_empty() {
+        return;
+    }
+    minor.collect(
+        gc::roots::get_roots(),
+        gc::weak::get_weak_refs(),
+        |wo_sz, tag| Value(alloc_major(wo_sz as std::ffi::c_ulonglong, tag) as usize),
+        |v| gc::collector::get_collector().darken(v),
+    );
+    gc::finalizer::get_finalizers().minor_collected(|v| minor.forward(v));
+    minor.reset();
+}
+
+// Slides the allocated blocks towards the lowest pools and gives back the pools left empty, after
+// finishing the mark and sweep in progress. Only the registered roots and the scanned fields of
+// blocks are updated, any other pointer into the heap is left dangling. None, with nothing done,
+// while there are ambiguous roots(see set_conservative_mark)
+pub fn compact() -> Option<Forwarding> {
+    if gc::roots::get_roots().has_ambiguous() {
+        return None;
+    }
+    if gc::collector::get_collector().is_marking() {
+        mark();
+    }
+    sweep_slice(usize::MAX);
+    // Only pointers in the major heap get updated
+    minor_collection();
+
+    let forwarding = get_global_allocator().compact(gc::roots::get_roots());
+    memprof::get_memprof().relocate(|addr| forwarding.forward(Value(addr)).map(|v| v.0));
+    gc::finalizer::get_finalizers().relocate(|val| forwarding.forward(val));
+    gc::weak::get_weak_refs().relocate(|val| forwarding.forward(val));
+    #[cfg(feature = "trace")]
+    for (old, new) in forwarding.moved() {
+        trace::record_move(*new.get_header().get_wosize().get_val(), old.0, new.0);
+    }
+    #[cfg(debug_assertions)]
+    {
+        let pool_starts = get_global_allocator()
+            .get_pool_iter()
+            .filter_map(|it| it.get_pool().blocks().next())
+            .map(|v| field_val(v, -1).0)
+            .collect::<Vec<usize>>();
+        unsafe { (*std::ptr::addr_of_mut!(MEM_RANGES)).retain(|r| pool_starts.contains(&r.0)) };
+    }
+    Some(forwarding)
+}
+
+// C side of compact. Returns the number of blocks that moved, -1 if it refused to run
+#[export_name = "compact"]
+pub extern "C" fn compact_c() -> isize {
+    compact().map_or(-1, |forwarding| forwarding.num_moved() as isize)
+}
+
+// `slot` is the address of a variable holding a pointer returned by alloc. The variable is read
+// on every mark, so it can keep being assigned to after registering it
+#[no_mangle]
+pub extern "C" fn register_global_root(slot: *mut *mut u8) {
+    gc::roots::get_roots().register_global(slot as *mut Value);
+}
+
+#[no_mangle]
+pub extern "C" fn remove_global_root(slot: *mut *mut u8) {
+    gc::roots::get_roots().remove_global(slot as *mut Value);
+}
+
+// Local roots are a stack, push them when entering a function and pop as many before returning
+#[no_mangle]
+pub extern "C" fn push_local_root(slot: *mut *mut u8) {
+    gc::roots::get_roots().push_local(slot as *mut Value);
+}
+
+#[no_mangle]
+pub extern "C" fn pop_local_roots(n: usize) {
+    gc::roots::get_roots().pop_locals(n);
+}
+
+// In conservative mode, every mark also scans the stack and registers of the thread it runs on,
+// and any word pointing inside an allocated block keeps it alive, for embedders that can't register
+// roots precisely. Such ambiguous roots can't be updated when a block moves, so while there are any
+// compact and set_minor_heap_wsz refuse to run. Returns -1 if conservative is true while there's a
+// minor heap, 0 otherwise
+#[no_mangle]
+pub extern "C" fn set_conservative_mark(conservative: bool) -> std::ffi::c_int {
+    if conservative && gc::minor::get_minor_heap().is_enabled() {
+        return -1;
+    }
+    gc::roots::get_roots().set_conservative(conservative);
+    0
+}
+
+// Every word of [start, start + len_bytes) is scanned like the stack is in conservative mode, on
+// every mark, whether conservative mode is on or not. These are ambiguous roots as well, returns
+// -1 if there's a minor heap and 0 otherwise
+#[no_mangle]
+pub extern "C" fn add_root_range(start: *mut u8, len_bytes: usize) -> std::ffi::c_int {
+    if gc::minor::get_minor_heap().is_enabled() {
+        return -1;
+    }
+    gc::roots::get_roots().add_range(start as usize, len_bytes);
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn remove_root_range(start: *mut u8) {
+    gc::roots::get_roots().remove_range(start as usize);
+}
+
+// The pointer alloc returned for the allocated block `ptr` points inside of, NULL if there's none.
+// Goes over the whole heap, the same lookup the conservative mark does
+#[no_mangle]
+pub extern "C" fn block_containing(ptr: *mut u8) -> *mut u8 {
+    let blocks = gc::mark::allocated_blocks(get_global_allocator());
+    gc::mark::block_containing(&blocks, ptr as usize)
+        .map_or(std::ptr::null_mut(), |v| v.0 as *mut u8)
+}
+
+// Registering NULL removes the callback
+#[no_mangle]
+pub extern "C" fn set_on_alloc(cb: Option<callbacks::AllocCallback>) {
+    callbacks::get_callbacks().on_alloc = cb;
+}
+
+#[no_mangle]
+pub extern "C" fn set_on_free(cb: Option<callbacks::FreeCallback>) {
+    callbacks::get_callbacks().on_free = cb;
+}
+
+#[no_mangle]
+pub extern "C" fn set_on_expand(cb: Option<callbacks::ExpandCallback>) {
+    callbacks::get_callbacks().on_expand = cb;
+}
+
+#[no_mangle]
+pub extern "C" fn set_on_sweep_done(cb: Option<callbacks::SweepDoneCallback>) {
+    callbacks::get_callbacks().on_sweep_done = cb;
+}
+
+// Starts sampling allocations, `rate` is the probability of any one word getting sampled
+#[no_mangle]
+pub extern "C" fn memprof_start(rate: f64) {
+    memprof::start(rate);
+}
+
+#[no_mangle]
+pub extern "C" fn memprof_stop() {
+    memprof::stop();
+}
+
+// Prints the live sampled blocks grouped by the backtrace of their allocation to stderr. Returns
+// the number of such blocks
+#[no_mangle]
+pub extern "C" fn memprof_report() -> usize {
+    let report = memprof::report();
+    eprint!("{report}");
+    report.call_sites.iter().map(|c| c.blocks).sum()
+}
+
+// Writes out whatever is buffered in the trace file. This is also done at exit
+#[cfg(feature = "trace")]
+#[no_mangle]
+pub extern "C" fn trace_flush() {
+    trace::flush();
+}
+
+#[no_mangle]
+pub extern "C" fn heap_verify() -> HeapVerifyReport {
+    get_global_allocator().heap_verify()
+}
+
+pub fn heap_stats() -> HeapStats {
+    get_global_allocator().heap_stats()
+}
+
+// C side of heap_stats. Fills in `stats`, does nothing if it's NULL
+#[export_name = "heap_stats"]
+pub extern "C" fn heap_stats_c(stats: Option<&mut HeapStats>) {
+    if let Some(stats) = stats {
+        *stats = heap_stats();
+    }
+}
+
+pub fn quick_stat() -> QuickStat {
+    get_global_allocator().quick_stat()
+}
+
+// C side of quick_stat. Fills in `stats`, does nothing if it's NULL
+#[export_name = "quick_stat"]
+pub extern "C" fn quick_stat_c(stats: Option<&mut QuickStat>) {
+    if let Some(stats) = stats {
+        *stats = quick_stat();
+    }
+}
+
+pub fn fragmentation() -> FragmentationReport {
+    get_global_allocator().fragmentation()
+}
+
+// C side of fragmentation. Fills in `report`, does nothing if it's NULL
+#[export_name = "fragmentation"]
+pub extern "C" fn fragmentation_c(report: Option<&mut FragmentationReport>) {
+    if let Some(report) = report {
+        *report = fragmentation();
+    }
+}
+
+pub fn heap_dump(path: impl AsRef<std::path::Path>) -> std::io::Result<()> {
+    let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+    get_global_allocator().heap_dump(&mut out)
+}
+
+pub fn heap_walker() -> HeapWalker<'static> {
+    get_global_allocator().heap_walker()
+}
+
+pub type HeapWalkCallback = extern "C" fn(
+    addr: *mut u8,
+    wo_sz: usize,
+    color: Color,
+    tag: u8,
+    in_free_list: bool,
+    ctx: *mut std::ffi::c_void,
+);
+
+// Calls `f` for every block in every pool, in address order. `ctx` is passed as is to `f`. The
+// callback must not alloc or dealloc. Returns the number of blocks visited, 0 if `f` is NULL
+#[no_mangle]
+pub extern "C" fn heap_walk(f: Option<HeapWalkCallback>, ctx: *mut std::ffi::c_void) -> usize {
+    let Some(f) = f else {
+        return 0;
+    };
+    heap_walker()
+        .map(|it| {
+            let hd = it.get_header();
+            f(
+                it.get_addr() as *mut u8,
+                *hd.get_wosize().get_val(),
+                hd.get_color(),
+                hd.get_tag(),
+                it.is_in_free_list(),
+                ctx,
+            )
+        })
+        .count()
+}
+
+pub fn leak_report() -> LeakReport {
+    #[allow(unused_mut)]
+    let mut report = get_global_allocator().leak_report();
+    #[cfg(feature = "trace")]
+    for group in report.groups.iter_mut() {
+        group.alloc_seqs = group.addrs.iter().map(|a| trace::alloc_seq(*a)).collect();
+    }
+    report
+}
+
+// C side of leak_report. Prints the report to stderr and returns the number of blocks that are
+// still allocated
+#[export_name = "leak_report"]
+pub extern "C" fn leak_report_c() -> usize {
+    let report = leak_report();
+    eprint!("{report}");
+    report.blocks
+}
+
+extern "C" fn leak_report_at_exit() {
+    leak_report_c();
+}
+
+// Prints the leak report to stderr when the process exits. Returns 0 on success
+#[no_mangle]
+pub extern "C" fn register_leak_report() -> std::ffi::c_int {
+    if utils::register_at_exit(leak_report_at_exit) {
+        0
+    } else {
+        -1
+    }
+}
+
+// Writes the allocator metrics as OpenMetrics text into `buf`, NUL terminated. Returns the length
+// of the text without the NUL, if that's >= `len` nothing is written and the call can be repeated
+// with a bigger buffer(like snprintf). `buf` must be valid for writes of `len` bytes, or NULL
+#[no_mangle]
+pub unsafe extern "C" fn metrics_openmetrics(buf: *mut std::ffi::c_char, len: usize) -> usize {
+    let text = metrics::openmetrics();
+    if !buf.is_null() && text.len() < len {
+        unsafe {
+            std::ptr::copy_nonoverlapping(text.as_ptr(), buf as *mut u8, text.len());
+            *buf.add(text.len()) = 0;
+        }
+    }
+    text.len()
+}
+
+// C side of heap_dump, `path` being a NUL terminated string. Returns 0 on success and -1 if the
+// file couldn't be written
+#[export_name = "heap_dump"]
+pub unsafe extern "C" fn heap_dump_c(path: *const std::ffi::c_char) -> std::ffi::c_int {
+    if path.is_null() {
+        return -1;
+    }
+    let path = unsafe { std::ffi::CStr::from_ptr(path) };
+    match path.to_str().map(heap_dump) {
+        Ok(Ok(())) => 0,
+        _ => -1,
+    }
 }
 
 #[cfg(test)]
 mod tests {
 
+    use std::sync::Mutex;
+
     use crate::{
-        alloc, dealloc,
+        add_root_range, alloc, alloc_with_header, block_color, block_tag, compact, compact_c,
+        dealloc,
         freelist::{allocator::get_global_allocator, fl::FreeList},
-        utils::whsize_wosize,
+        heap_stats, heap_walk, mark, remove_root_range,
+        replay::{replay, ReplayTarget},
+        set_block_color, set_block_tag, set_conservative_mark, set_minor_heap_wsz, set_on_alloc,
+        set_on_expand, set_on_free, set_on_sweep_done, sweep,
+        trace::{read_trace, TraceOp, TraceRecord},
+        utils::{whsize_wosize, WORD_SIZE},
+        value::Value,
+        CAML_BLACK, CAML_BLUE, CAML_GRAY, CAML_WHITE, CUSTOM_TAG, STRING_TAG,
     };
 
+    // Tests going through the global allocator can't run at the same time. They all give back
+    // what they allocate, newest first, so that the free list ends up as one block again
+    static GLOBAL_HEAP: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn block_header_test() {
+        let _heap = GLOBAL_HEAP.lock().unwrap_or_else(|e| e.into_inner());
+
+        let bp = alloc_with_header((3 << 10) + CAML_WHITE + STRING_TAG as usize);
+        assert_eq!(*Value(bp as usize).get_header().get_wosize().get_val(), 3);
+        assert_eq!(block_tag(bp), STRING_TAG);
+        assert_eq!(block_color(bp), CAML_WHITE);
+
+        set_block_tag(bp, CUSTOM_TAG);
+        assert_eq!(block_tag(bp), CUSTOM_TAG);
+        assert_eq!(set_block_color(bp, CAML_BLACK), 0);
+        assert_eq!(block_color(bp), CAML_BLACK);
+        for color in [CAML_GRAY, CAML_BLUE, 1 << 20] {
+            assert_eq!(set_block_color(bp, color), -1);
+        }
+        assert_eq!(block_color(bp), CAML_BLACK);
+        assert_eq!(*Value(bp as usize).get_header().get_wosize().get_val(), 3);
+
+        for color in [CAML_GRAY, CAML_BLUE] {
+            assert!(alloc_with_header((3 << 10) + color).is_null());
+        }
+        dealloc(bp);
+    }
+
+    #[test]
+    fn ambiguous_roots_test() {
+        let _heap = GLOBAL_HEAP.lock().unwrap_or_else(|e| e.into_inner());
+
+        let mut word = 0usize;
+        let range = std::ptr::addr_of_mut!(word) as *mut u8;
+        assert_eq!(add_root_range(range, std::mem::size_of::<usize>()), 0);
+        assert!(compact().is_none());
+        assert_eq!(compact_c(), -1);
+        assert_eq!(set_minor_heap_wsz(1024), -1);
+        remove_root_range(range);
+
+        assert_eq!(set_minor_heap_wsz(1024), 0);
+        assert_eq!(set_conservative_mark(true), -1);
+        assert_eq!(add_root_range(range, std::mem::size_of::<usize>()), -1);
+        assert_eq!(set_minor_heap_wsz(0), 0);
+
+        assert_eq!(set_conservative_mark(true), 0);
+        assert_eq!(set_minor_heap_wsz(1024), -1);
+        assert_eq!(set_conservative_mark(false), 0);
+    }
+
     #[test]
     fn tests() {
+        let _heap = GLOBAL_HEAP.lock().unwrap_or_else(|e| e.into_inner());
+
         // 1st allocation
         let req1: usize = 1024 * 8;
         let allocd_mem1 = alloc(req1 as u64);
@@ -163,4 +901,170 @@ mod tests {
         // //since it's first fit this should pass
         // assert_eq!(alloc(256 * 1024), alloc_mem);
     }
+
+    // (callback, pointer, words) in the order the callbacks ran
+    static EVENTS: Mutex<Vec<(&str, usize, usize)>> = Mutex::new(vec![]);
+
+    extern "C" fn on_alloc(ptr: *mut u8, wo_sz: usize) {
+        EVENTS.lock().unwrap().push(("alloc", ptr as usize, wo_sz));
+    }
+
+    extern "C" fn on_free(ptr: *mut u8, wo_sz: usize) {
+        EVENTS.lock().unwrap().push(("free", ptr as usize, wo_sz));
+    }
+
+    extern "C" fn on_expand(pool: *mut u8, pool_wo_sz: usize) {
+        EVENTS
+            .lock()
+            .unwrap()
+            .push(("expand", pool as usize, pool_wo_sz));
+    }
+
+    extern "C" fn on_sweep_done(reclaimed: usize) {
+        EVENTS.lock().unwrap().push(("sweep_done", 0, reclaimed));
+    }
+
+    #[test]
+    fn callbacks_test() {
+        let _heap = GLOBAL_HEAP.lock().unwrap_or_else(|e| e.into_inner());
+
+        set_on_alloc(Some(on_alloc));
+        set_on_free(Some(on_free));
+        set_on_expand(Some(on_expand));
+        set_on_sweep_done(Some(on_sweep_done));
+
+        // More than the whole heap, it has to grow. With no_expand_heap it only can while it has
+        // no pool yet
+        let heap_words = heap_stats().heap_words;
+        let grows = !cfg!(feature = "no_expand_heap") || heap_words == 0;
+        let big_wo_sz = if grows { heap_words + 1 } else { 16 };
+        let big = alloc(big_wo_sz as u64);
+        let small = alloc(3);
+        dealloc(small);
+        dealloc(big);
+        // Left for the sweep, nothing points to it
+        let garbage = alloc(3);
+        mark();
+        sweep();
+
+        let mut events = std::mem::take(&mut *EVENTS.lock().unwrap());
+        if grows {
+            let ("expand", pool, pool_wo_sz) = events.remove(0) else {
+                panic!("{events:?}")
+            };
+            assert!(pool < big as usize);
+            assert!(big as usize + big_wo_sz * WORD_SIZE <= pool + pool_wo_sz * WORD_SIZE);
+        }
+        assert_eq!(
+            events,
+            [
+                ("alloc", big as usize, big_wo_sz),
+                ("alloc", small as usize, 3),
+                ("free", small as usize, 3),
+                ("free", big as usize, big_wo_sz),
+                ("alloc", garbage as usize, 3),
+                ("sweep_done", 0, 3 + 1),
+            ]
+        );
+
+        // Registering NULL removes them
+        set_on_alloc(None);
+        set_on_free(None);
+        set_on_expand(None);
+        set_on_sweep_done(None);
+        dealloc(alloc(if cfg!(feature = "no_expand_heap") {
+            16
+        } else {
+            heap_stats().heap_words as u64 + 1
+        }));
+        mark();
+        sweep();
+        assert!(EVENTS.lock().unwrap().is_empty());
+
+        // Gives back the pools added here
+        assert!(compact().is_some());
+    }
+
+    extern "C" fn count_walked(
+        _ptr: *mut u8,
+        _wo_sz: usize,
+        _color: usize,
+        _tag: u8,
+        _in_free_list: bool,
+        ctx: *mut std::ffi::c_void,
+    ) {
+        unsafe { *(ctx as *mut usize) += 1 };
+    }
+
+    #[test]
+    fn heap_walk_test() {
+        let _heap = GLOBAL_HEAP.lock().unwrap_or_else(|e| e.into_inner());
+
+        let bp = alloc(2);
+        let mut walked = 0usize;
+        let ctx = std::ptr::addr_of_mut!(walked) as *mut std::ffi::c_void;
+        assert_eq!(heap_walk(Some(count_walked), ctx), walked);
+        assert!(walked >= 1);
+        assert_eq!(heap_walk(None, ctx), 0);
+        dealloc(bp);
+    }
+
+    #[cfg(feature = "trace")]
+    #[test]
+    fn trace_alloc_seq_test() {
+        let _heap = GLOBAL_HEAP.lock().unwrap_or_else(|e| e.into_inner());
+
+        let garbage = alloc(3);
+        assert!(crate::trace::alloc_seq(garbage as usize).is_some());
+        mark();
+        sweep();
+        assert_eq!(crate::trace::alloc_seq(garbage as usize), None);
+    }
+
+    #[test]
+    fn replay_test() {
+        let _heap = GLOBAL_HEAP.lock().unwrap_or_else(|e| e.into_inner());
+
+        let record = |op, size, addr| TraceRecord {
+            op,
+            size,
+            addr,
+            new_addr: 0,
+            time_ns: 0,
+        };
+        let mut trace = vec![];
+        for r in [
+            record(TraceOp::Alloc, 2, 0x100),
+            record(TraceOp::Alloc, 3, 0x200),
+            TraceRecord {
+                new_addr: 0x300,
+                ..record(TraceOp::Move, 3, 0x200)
+            },
+            record(TraceOp::Sweep, 0, 0),
+            record(TraceOp::Dealloc, 3, 0x999),
+            // Known by the address it was moved to
+            record(TraceOp::Dealloc, 3, 0x300),
+            record(TraceOp::Dealloc, 2, 0x100),
+        ] {
+            r.write_to(&mut trace).unwrap();
+        }
+        let records = read_trace(&mut trace.as_slice()).unwrap();
+
+        for target in [ReplayTarget::NextFit, ReplayTarget::System] {
+            let report = replay(&records, target);
+            assert_eq!(report.target, target);
+            assert_eq!(report.allocs, 2);
+            assert_eq!(report.deallocs, 2);
+            assert_eq!(report.unknown_deallocs, 1);
+            assert_eq!(report.skipped_sweeps, 1);
+            assert_eq!(report.peak_live_words, 3 + 4);
+            if target == ReplayTarget::NextFit {
+                assert!(report.peak_heap_words.unwrap() >= 3 + 4);
+                assert!(report.external_fragmentation.is_some());
+            } else {
+                assert_eq!(report.peak_heap_words, None);
+                assert_eq!(report.external_fragmentation, None);
+            }
+        }
+    }
 }
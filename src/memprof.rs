@@ -0,0 +1,273 @@
+use std::{
+    backtrace::Backtrace,
+    collections::{BTreeMap, HashMap},
+    fmt::{self, Display},
+};
+
+use crate::{colors::CAML_WHITE, value::Value};
+
+// Like OCaml's Memprof, every word that gets allocated is sampled with probability `rate`. A
+// block with at least one sampled word gets the backtrace of its alloc call recorded, which is
+// kept around until the block is dealloc'd or reclaimed by sweep.
+pub struct Memprof {
+    rate: f64,
+    // Words left to allocate before the next sampled word
+    countdown: usize,
+    rng: u64,
+    // addr returned by alloc -> sample
+    tracked: BTreeMap<usize, Sample>,
+}
+
+struct Sample {
+    wo_sz: usize,
+    // Number of words of the block that got sampled, usually 1
+    samples: usize,
+    backtrace: Backtrace,
+}
+
+// Live sampled blocks that were allocated from the same backtrace
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallSite {
+    pub backtrace: String,
+    pub blocks: usize,
+    pub samples: usize,
+    // headers included
+    pub words: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MemprofReport {
+    pub rate: f64,
+    // Sorted by words, biggest first
+    pub call_sites: Vec<CallSite>,
+}
+
+impl Memprof {
+    pub const fn new() -> Self {
+        Memprof {
+            rate: 0.0,
+            countdown: usize::MAX,
+            rng: 0x2545_f491_4f6c_dd1d,
+            tracked: BTreeMap::new(),
+        }
+    }
+
+    pub fn get_rate(&self) -> f64 {
+        self.rate
+    }
+
+    // rate <= 0 stops sampling, blocks already sampled are still tracked. rate >= 1 samples
+    // every block
+    pub fn set_rate(&mut self, rate: f64) {
+        self.rate = rate.clamp(0.0, 1.0);
+        self.countdown = self.next_countdown();
+    }
+
+    // xorshift64*, in (0, 1]
+    fn next_uniform(&mut self) -> f64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        let r = self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11;
+        (r + 1) as f64 / (1u64 << 53) as f64
+    }
+
+    // Number of words up to and including the next sampled one, geometrically distributed
+    fn next_countdown(&mut self) -> usize {
+        if self.rate <= 0.0 {
+            return usize::MAX;
+        }
+        if self.rate >= 1.0 {
+            return 1;
+        }
+        let u = self.next_uniform();
+        (u.ln() / (1.0 - self.rate).ln()).floor() as usize + 1
+    }
+
+    pub fn on_alloc(&mut self, addr: usize, wo_sz: usize) {
+        if self.rate <= 0.0 {
+            return;
+        }
+
+        let mut words_left = wo_sz + 1;
+        let mut samples = 0;
+        while self.countdown <= words_left {
+            samples += 1;
+            words_left -= self.countdown;
+            self.countdown = self.next_countdown();
+        }
+        self.countdown -= words_left;
+
+        if samples != 0 {
+            self.tracked.insert(
+                addr,
+                Sample {
+                    wo_sz,
+                    samples,
+                    backtrace: Backtrace::force_capture(),
+                },
+            );
+        }
+    }
+
+    pub fn on_dealloc(&mut self, addr: usize) {
+        if !self.tracked.is_empty() {
+            self.tracked.remove(&addr);
+        }
+    }
+
+    // Sampled blocks moved by a compaction, `forward` gives the new address of a block
+    pub fn relocate(&mut self, forward: impl Fn(usize) -> Option<usize>) {
+        self.tracked = std::mem::take(&mut self.tracked)
+            .into_iter()
+            .map(|(addr, sample)| (forward(addr).unwrap_or(addr), sample))
+            .collect();
+    }
+
+    // Has to be called before the sweep, once it's done a reclaimed block may have been merged
+    // into its neighbour and its header can't be trusted anymore
+    pub fn before_sweep(&mut self) {
+        self.tracked
+            .retain(|addr, _| Value(*addr).get_header().get_color() != CAML_WHITE);
+    }
+
+    pub fn num_tracked(&self) -> usize {
+        self.tracked.len()
+    }
+
+    pub fn report(&self) -> MemprofReport {
+        let mut call_sites: HashMap<String, CallSite> = HashMap::new();
+        for sample in self.tracked.values() {
+            let backtrace = sample.backtrace.to_string();
+            let site = call_sites
+                .entry(backtrace.clone())
+                .or_insert_with(|| CallSite {
+                    backtrace,
+                    blocks: 0,
+                    samples: 0,
+                    words: 0,
+                });
+            site.blocks += 1;
+            site.samples += sample.samples;
+            site.words += sample.wo_sz + 1;
+        }
+
+        let mut call_sites = call_sites.into_values().collect::<Vec<CallSite>>();
+        call_sites.sort_by_key(|c| std::cmp::Reverse(c.words));
+        MemprofReport {
+            rate: self.rate,
+            call_sites,
+        }
+    }
+}
+
+impl Default for Memprof {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Display for MemprofReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "memprof: {} call sites, sampling rate {}",
+            self.call_sites.len(),
+            self.rate
+        )?;
+        for site in &self.call_sites {
+            writeln!(
+                f,
+                "{} blocks, {} words, {} samples(~{:.0} words allocated from here) at:",
+                site.blocks,
+                site.words,
+                site.samples,
+                site.samples as f64 / self.rate
+            )?;
+            writeln!(f, "{}", site.backtrace)?;
+        }
+        Ok(())
+    }
+}
+
+static mut MEMPROF: Memprof = Memprof::new();
+
+pub fn get_memprof() -> &'static mut Memprof {
+    unsafe { &mut *std::ptr::addr_of_mut!(MEMPROF) }
+}
+
+pub fn start(rate: f64) {
+    get_memprof().set_rate(rate);
+}
+
+pub fn stop() {
+    get_memprof().set_rate(0.0);
+}
+
+pub fn report() -> MemprofReport {
+    get_memprof().report()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        colors::{CAML_BLACK, CAML_WHITE},
+        header::Header,
+        value::Value,
+    };
+
+    use super::Memprof;
+
+    #[test]
+    fn memprof_test() {
+        let mut memprof = Memprof::new();
+
+        // Not started, nothing is sampled
+        memprof.on_alloc(0x1000, 10);
+        assert_eq!(memprof.num_tracked(), 0);
+
+        // Both allocated from the same line, so they share the call site
+        memprof.set_rate(1.0);
+        for (addr, wo_sz) in [(0x1000, 10), (0x2000, 20)] {
+            memprof.on_alloc(addr, wo_sz);
+        }
+        let report = memprof.report();
+        assert_eq!(report.call_sites.len(), 1);
+        assert_eq!(report.call_sites[0].blocks, 2);
+        assert_eq!(report.call_sites[0].words, 11 + 21);
+        assert_eq!(report.call_sites[0].samples, 11 + 21);
+
+        memprof.on_dealloc(0x1000);
+        assert_eq!(memprof.num_tracked(), 1);
+
+        // With a rate of 0.01, about 1 word in 100 gets sampled
+        memprof.set_rate(0.01);
+        for i in 0..1000 {
+            memprof.on_alloc(0x10000 + i * 0x100, 9);
+        }
+        let samples: usize = memprof.report().call_sites.iter().map(|c| c.samples).sum();
+        assert!((50..=200).contains(&samples), "{samples}");
+    }
+
+    #[test]
+    fn memprof_sweep_test() {
+        let mut memprof = Memprof::new();
+        memprof.set_rate(1.0);
+
+        // [hd, field] twice
+        let mut mem = [
+            Header::new(1, CAML_WHITE, 0),
+            Header::new(0, CAML_WHITE, 0),
+            Header::new(1, CAML_BLACK, 0),
+            Header::new(0, CAML_WHITE, 0),
+        ];
+        let dead = Value(std::ptr::addr_of_mut!(mem[1]) as usize);
+        let live = Value(std::ptr::addr_of_mut!(mem[3]) as usize);
+        memprof.on_alloc(dead.0, 1);
+        memprof.on_alloc(live.0, 1);
+
+        memprof.before_sweep();
+        assert_eq!(memprof.num_tracked(), 1);
+        assert!(memprof.tracked.contains_key(&live.0));
+    }
+}
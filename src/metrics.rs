@@ -0,0 +1,118 @@
+use std::fmt::Write;
+
+use crate::{
+    fragmentation, heap_stats, quick_stat, utils::WORD_SIZE, FragmentationReport, HeapStats,
+    QuickStat,
+};
+
+const PREFIX: &str = "rust_allocator";
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Gauge,
+    Counter,
+}
+
+// OpenMetrics text exposition of everything heap_stats, quick_stat and fragmentation know about
+pub fn render_openmetrics(
+    stats: &HeapStats,
+    quick: &QuickStat,
+    frag: &FragmentationReport,
+) -> String {
+    use Kind::*;
+
+    #[rustfmt::skip]
+    let metrics: [(Kind, &str, &str, String); 18] = [
+        (Gauge, "word_size_bytes", "Size of a word in bytes.", WORD_SIZE.to_string()),
+        (Gauge, "heap_words", "Total size of all the pools in words.", stats.heap_words.to_string()),
+        (Gauge, "top_heap_words", "Largest size the heap has had in words.", stats.top_heap_words.to_string()),
+        (Gauge, "free_words", "Words in the free list, headers included.", stats.free_words.to_string()),
+        (Gauge, "live_words", "Words in allocated blocks, headers included.", stats.live_words.to_string()),
+        (Gauge, "live_blocks", "Number of allocated blocks.", stats.live_blocks.to_string()),
+        (Gauge, "free_blocks", "Number of blocks in the free list.", stats.free_blocks.to_string()),
+        (Gauge, "largest_free_words", "Size of the largest free block in words.", stats.largest_free.to_string()),
+        (Gauge, "fragments", "Zero sized headers wasting a word each.", stats.fragments.to_string()),
+        (Gauge, "pools", "Number of pools in the heap.", stats.pools.to_string()),
+        (Gauge, "external_fragmentation_ratio", "1 - largest free block / total free words.", frag.external_fragmentation.to_string()),
+        (Counter, "heap_expansions", "Number of times the heap has been expanded.", stats.heap_expansions.to_string()),
+        (Counter, "allocations", "Number of blocks allocated.", quick.allocations.to_string()),
+        (Counter, "allocated_words", "Words allocated, headers included.", quick.allocated_words.to_string()),
+        (Counter, "frees", "Number of blocks given back through dealloc.", quick.frees.to_string()),
+        (Counter, "sweeps", "Number of sweeps.", quick.sweeps.to_string()),
+        (Counter, "swept_words", "Words given back to the free list by sweeps.", quick.swept_words.to_string()),
+        (Counter, "free_list_visits", "Free list nodes visited while searching it.", quick.fl_visited.to_string()),
+    ];
+
+    let mut out = String::new();
+    for (kind, name, help, value) in metrics {
+        // Samples of a counter need the _total suffix, the family itself doesn't have it
+        let (kind, suffix) = match kind {
+            Gauge => ("gauge", ""),
+            Counter => ("counter", "_total"),
+        };
+        let _ = writeln!(out, "# TYPE {PREFIX}_{name} {kind}");
+        let _ = writeln!(out, "# HELP {PREFIX}_{name} {help}");
+        let _ = writeln!(out, "{PREFIX}_{name}{suffix} {value}");
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+// Metrics of the global allocator. This walks the whole heap
+pub fn openmetrics() -> String {
+    render_openmetrics(&heap_stats(), &quick_stat(), &fragmentation())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{FragmentationReport, HeapStats, QuickStat};
+
+    use super::render_openmetrics;
+
+    #[test]
+    fn openmetrics_test() {
+        let stats = HeapStats {
+            heap_words: 1024,
+            pools: 2,
+            ..Default::default()
+        };
+        let quick = QuickStat {
+            sweeps: 3,
+            ..Default::default()
+        };
+        let frag = FragmentationReport {
+            external_fragmentation: 0.25,
+            ..Default::default()
+        };
+        let out = render_openmetrics(&stats, &quick, &frag);
+
+        assert!(out.ends_with("# EOF\n"));
+        let mut samples = vec![];
+        let mut families = vec![];
+        for line in out.lines().filter(|l| *l != "# EOF") {
+            if let Some(rest) = line.strip_prefix("# TYPE ") {
+                let (name, kind) = rest.split_once(' ').unwrap();
+                assert!(kind == "gauge" || kind == "counter");
+                families.push((name.to_string(), kind.to_string()));
+            } else if line.starts_with("# HELP ") {
+                assert!(line.ends_with('.'));
+            } else {
+                let (name, value) = line.split_once(' ').unwrap();
+                let (family, kind) = families.last().unwrap();
+                let expected = match kind.as_str() {
+                    "counter" => format!("{family}_total"),
+                    _ => family.clone(),
+                };
+                assert_eq!(name, expected);
+                samples.push((name.to_string(), value.parse::<f64>().unwrap()));
+            }
+        }
+        assert_eq!(samples.len(), families.len());
+
+        let value = |name: &str| samples.iter().find(|s| s.0 == name).unwrap().1;
+        assert_eq!(value("rust_allocator_heap_words"), 1024.0);
+        assert_eq!(value("rust_allocator_pools"), 2.0);
+        assert_eq!(value("rust_allocator_sweeps_total"), 3.0);
+        assert_eq!(value("rust_allocator_external_fragmentation_ratio"), 0.25);
+    }
+}
@@ -0,0 +1,141 @@
+use std::{
+    alloc::Layout,
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    alloc, dealloc, fragmentation, heap_stats, quick_stat,
+    trace::{TraceOp, TraceRecord},
+    utils::WORD_SIZE,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayTarget {
+    // The global NfAllocator, through alloc and dealloc
+    NextFit,
+    // std::alloc::alloc and std::alloc::dealloc
+    System,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayReport {
+    pub target: ReplayTarget,
+    pub allocs: usize,
+    pub deallocs: usize,
+    // Sweeps aren't replayed, the trace doesn't know which blocks were unreachable at that point
+    pub skipped_sweeps: usize,
+    // Deallocs of an address that no replayed alloc returned(the trace was started late, or it
+    // was a double free)
+    pub unknown_deallocs: usize,
+    pub elapsed: Duration,
+    // Largest number of words the replayed program had alive at once, headers included
+    pub peak_live_words: usize,
+    // Only known for NextFit, largest the heap has been during the replay, counting the pools it
+    // already had when the replay started
+    pub peak_heap_words: Option<usize>,
+    // Only known for NextFit, external fragmentation right after the last record
+    pub external_fragmentation: Option<f64>,
+}
+
+fn target_alloc(target: ReplayTarget, wo_sz: usize) -> *mut u8 {
+    match target {
+        ReplayTarget::NextFit => alloc(wo_sz as u64),
+        ReplayTarget::System => {
+            let layout = system_layout(wo_sz);
+            let ptr = unsafe { std::alloc::alloc(layout) };
+            if ptr.is_null() {
+                std::alloc::handle_alloc_error(layout);
+            }
+            ptr
+        }
+    }
+}
+
+fn target_dealloc(target: ReplayTarget, ptr: *mut u8, wo_sz: usize) {
+    match target {
+        ReplayTarget::NextFit => dealloc(ptr),
+        ReplayTarget::System => unsafe { std::alloc::dealloc(ptr, system_layout(wo_sz)) },
+    }
+}
+
+fn system_layout(wo_sz: usize) -> Layout {
+    Layout::from_size_align(wo_sz.max(1) * WORD_SIZE, WORD_SIZE).unwrap()
+}
+
+// Runs the records in order, mapping the addresses in the trace to the pointers that the target
+// gives back. Whatever is still alive once the trace ends is freed after the measurements are
+// taken, so replay can be called over and over without growing the heap.
+pub fn replay(records: &[TraceRecord], target: ReplayTarget) -> ReplayReport {
+    let mut report = ReplayReport {
+        target,
+        allocs: 0,
+        deallocs: 0,
+        skipped_sweeps: 0,
+        unknown_deallocs: 0,
+        elapsed: Duration::ZERO,
+        peak_live_words: 0,
+        peak_heap_words: None,
+        external_fragmentation: None,
+    };
+
+    // recorded addr -> (live pointer, wosize)
+    let mut live: HashMap<u64, (*mut u8, usize)> = HashMap::with_capacity(records.len() / 2);
+    let mut live_words = 0;
+    // The heap only grows when it's expanded, so its size is only looked at again then
+    let mut heap_expansions = 0;
+    if target == ReplayTarget::NextFit {
+        heap_expansions = quick_stat().heap_expansions;
+        report.peak_heap_words = Some(heap_stats().heap_words);
+    }
+
+    let start = Instant::now();
+    for record in records {
+        match record.op {
+            TraceOp::Alloc => {
+                let wo_sz = (record.size as usize).max(1);
+                let ptr = target_alloc(target, wo_sz);
+                if let Some((old, old_wo_sz)) = live.insert(record.addr, (ptr, wo_sz)) {
+                    // The recorded program reused an address we never saw being freed
+                    target_dealloc(target, old, old_wo_sz);
+                    live_words -= old_wo_sz + 1;
+                }
+                live_words += wo_sz + 1;
+                report.peak_live_words = report.peak_live_words.max(live_words);
+                report.allocs += 1;
+                if let Some(peak) = report.peak_heap_words.as_mut() {
+                    if quick_stat().heap_expansions != heap_expansions {
+                        heap_expansions = quick_stat().heap_expansions;
+                        *peak = (*peak).max(heap_stats().heap_words);
+                    }
+                }
+            }
+            TraceOp::Dealloc => match live.remove(&record.addr) {
+                Some((ptr, wo_sz)) => {
+                    target_dealloc(target, ptr, wo_sz);
+                    live_words -= wo_sz + 1;
+                    report.deallocs += 1;
+                }
+                None => report.unknown_deallocs += 1,
+            },
+            TraceOp::Sweep => report.skipped_sweeps += 1,
+            // Where the block is in the target doesn't change, only the address it's known by
+            TraceOp::Move => {
+                if let Some(block) = live.remove(&record.addr) {
+                    live.insert(record.new_addr, block);
+                }
+            }
+        }
+    }
+    report.elapsed = start.elapsed();
+
+    if target == ReplayTarget::NextFit {
+        report.external_fragmentation = Some(fragmentation().external_fragmentation);
+    }
+
+    for (_, (ptr, wo_sz)) in live {
+        target_dealloc(target, ptr, wo_sz);
+    }
+
+    report
+}
@@ -0,0 +1,56 @@
+// Same values as OCaml's runtime/caml/mlvalues.h. Blocks with a tag below NO_SCAN_TAG hold
+// values in all their fields, the rest hold raw data which the mark phase must never look into.
+pub const LAZY_TAG: u8 = 246;
+pub const CLOSURE_TAG: u8 = 247;
+pub const OBJECT_TAG: u8 = 248;
+pub const INFIX_TAG: u8 = 249;
+pub const FORWARD_TAG: u8 = 250;
+pub const NO_SCAN_TAG: u8 = 251;
+pub const ABSTRACT_TAG: u8 = 251;
+pub const STRING_TAG: u8 = 252;
+pub const DOUBLE_TAG: u8 = 253;
+pub const DOUBLE_ARRAY_TAG: u8 = 254;
+pub const CUSTOM_TAG: u8 = 255;
+
+// Fields with the low bit set are immediate ints(OCaml's Is_long), never pointers
+pub fn is_long(word: usize) -> bool {
+    word & 1 == 1
+}
+
+pub fn val_long(n: isize) -> usize {
+    ((n << 1) + 1) as usize
+}
+
+pub fn long_val(word: usize) -> isize {
+    (word as isize) >> 1
+}
+
+// Field 1 of a closure is its closinfo, an immediate holding the arity in the top 8 bits and the
+// index of the first field of the environment in the rest. Fields before that are code pointers,
+// closinfos and infix headers of mutually recursive functions, none of which are values.
+pub fn start_env_closinfo(info: usize) -> usize {
+    (info << 8) >> 9
+}
+
+pub fn arity_closinfo(info: usize) -> isize {
+    (info as isize) >> 56
+}
+
+pub fn make_closinfo(arity: isize, start_env: usize) -> usize {
+    ((arity as usize) << 56) + (start_env << 1) + 1
+}
+
+#[cfg(test)]
+mod tags_tests {
+    use super::*;
+
+    #[test]
+    fn closinfo_test() {
+        let info = make_closinfo(-3, 5);
+        assert!(is_long(info));
+        assert_eq!(start_env_closinfo(info), 5);
+        assert_eq!(arity_closinfo(info), -3);
+        assert_eq!(long_val(val_long(-42)), -42);
+        assert!(!is_long(8));
+    }
+}
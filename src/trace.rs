@@ -0,0 +1,265 @@
+use std::io::{self, Read, Write};
+
+// A trace is just these records laid out back to back, each one RECORD_SIZE bytes, all numbers
+// little endian:
+//
+// | op: u8 | size: u64 | addr: u64 | new_addr: u64 | time_ns: u64 |
+//
+// For Alloc, size is the requested wosize and addr is what alloc returned. For Dealloc, size is
+// the wosize of the block being freed and addr is the pointer passed to dealloc. For Sweep, size
+// is the number of words the sweep gave back and addr is 0. For Move, a block compact moved,
+// size is its wosize, addr is where it was and new_addr is where it is now. new_addr is 0 for
+// every other op. time_ns is counted from the first record of
+// the process
+pub const RECORD_SIZE: usize = 1 + 8 + 8 + 8 + 8;
+
+// Name of the env var holding the path of the trace file
+pub const TRACE_FILE_ENV: &str = "ALLOC_TRACE_FILE";
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceOp {
+    Alloc = 0,
+    Dealloc = 1,
+    Sweep = 2,
+    Move = 3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub op: TraceOp,
+    pub size: u64,
+    pub addr: u64,
+    pub new_addr: u64,
+    pub time_ns: u64,
+}
+
+impl TraceRecord {
+    pub fn to_bytes(&self) -> [u8; RECORD_SIZE] {
+        let mut buf = [0u8; RECORD_SIZE];
+        buf[0] = self.op as u8;
+        buf[1..9].copy_from_slice(&self.size.to_le_bytes());
+        buf[9..17].copy_from_slice(&self.addr.to_le_bytes());
+        buf[17..25].copy_from_slice(&self.new_addr.to_le_bytes());
+        buf[25..33].copy_from_slice(&self.time_ns.to_le_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8; RECORD_SIZE]) -> Option<Self> {
+        let op = match buf[0] {
+            0 => TraceOp::Alloc,
+            1 => TraceOp::Dealloc,
+            2 => TraceOp::Sweep,
+            3 => TraceOp::Move,
+            _ => return None,
+        };
+        let u64_at = |i: usize| u64::from_le_bytes(buf[i..i + 8].try_into().unwrap());
+        Some(TraceRecord {
+            op,
+            size: u64_at(1),
+            addr: u64_at(9),
+            new_addr: u64_at(17),
+            time_ns: u64_at(25),
+        })
+    }
+
+    pub fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        out.write_all(&self.to_bytes())
+    }
+}
+
+// Reads back everything that was written with TraceRecord::write_to. A truncated record at the
+// end(the process died halfway through writing it) is dropped.
+pub fn read_trace(input: &mut impl Read) -> io::Result<Vec<TraceRecord>> {
+    let mut bytes = vec![];
+    input.read_to_end(&mut bytes)?;
+
+    bytes
+        .chunks_exact(RECORD_SIZE)
+        .map(|chunk| {
+            TraceRecord::from_bytes(chunk.try_into().unwrap()).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "unknown op in trace record")
+            })
+        })
+        .collect()
+}
+
+#[cfg(feature = "trace")]
+mod recorder {
+    use std::{
+        collections::HashMap,
+        fs::{File, OpenOptions},
+        io::{BufWriter, Write},
+        sync::Once,
+        time::Instant,
+    };
+
+    use crate::utils::register_at_exit;
+
+    use super::{TraceOp, TraceRecord, TRACE_FILE_ENV};
+
+    struct Recorder {
+        // None when the env var isn't set or the file can't be opened, nothing gets written then
+        // but sequence numbers are still handed out
+        out: Option<BufWriter<File>>,
+        start: Instant,
+        // Index of the next record, counting every op
+        next_seq: u64,
+        // addr returned by alloc -> seq of that alloc, for the blocks that haven't been freed yet
+        live_seqs: HashMap<usize, u64>,
+    }
+
+    static mut RECORDER: Option<Recorder> = None;
+
+    extern "C" fn flush_at_exit() {
+        flush();
+    }
+
+    fn get_recorder() -> &'static mut Recorder {
+        static ONCE: Once = Once::new();
+        ONCE.call_once(|| {
+            // One trace per process, a trace left by an earlier run is overwritten
+            let out = std::env::var_os(TRACE_FILE_ENV)
+                .and_then(|path| {
+                    OpenOptions::new()
+                        .write(true)
+                        .create(true)
+                        .truncate(true)
+                        .open(path)
+                        .ok()
+                })
+                .map(BufWriter::new);
+            if out.is_some() {
+                register_at_exit(flush_at_exit);
+            }
+            unsafe {
+                *std::ptr::addr_of_mut!(RECORDER) = Some(Recorder {
+                    out,
+                    start: Instant::now(),
+                    next_seq: 0,
+                    live_seqs: HashMap::new(),
+                });
+            }
+        });
+        unsafe { (*std::ptr::addr_of_mut!(RECORDER)).as_mut().unwrap() }
+    }
+
+    pub fn record(op: TraceOp, size: usize, addr: usize) {
+        record_with_new_addr(op, size, addr, 0);
+    }
+
+    // A block of wosize `wo_sz` that compact moved from `addr` to `new_addr`
+    pub fn record_move(wo_sz: usize, addr: usize, new_addr: usize) {
+        record_with_new_addr(TraceOp::Move, wo_sz, addr, new_addr);
+    }
+
+    fn record_with_new_addr(op: TraceOp, size: usize, addr: usize, new_addr: usize) {
+        let recorder = get_recorder();
+        let seq = recorder.next_seq;
+        recorder.next_seq += 1;
+
+        match op {
+            TraceOp::Alloc => {
+                recorder.live_seqs.insert(addr, seq);
+            }
+            TraceOp::Dealloc => {
+                recorder.live_seqs.remove(&addr);
+            }
+            TraceOp::Sweep => {}
+            TraceOp::Move => {
+                if let Some(alloc_seq) = recorder.live_seqs.remove(&addr) {
+                    recorder.live_seqs.insert(new_addr, alloc_seq);
+                }
+            }
+        }
+
+        if let Some(out) = recorder.out.as_mut() {
+            let record = TraceRecord {
+                op,
+                size: size as u64,
+                addr: addr as u64,
+                new_addr: new_addr as u64,
+                time_ns: recorder.start.elapsed().as_nanos() as u64,
+            };
+            // A trace that can't be written shouldn't take the allocator down with it
+            let _ = record.write_to(out);
+        }
+    }
+
+    // Has to be called once a sweep is over, `is_allocated` tells whether there's still an
+    // allocated block at an address. The sweep record doesn't say which blocks it reclaimed, so
+    // without this they would be remembered forever, promoted blocks included
+    pub fn forget_reclaimed(is_allocated: impl Fn(usize) -> bool) {
+        get_recorder()
+            .live_seqs
+            .retain(|addr, _| is_allocated(*addr));
+    }
+
+    // Sequence number(index in the trace) of the alloc that returned `addr`, if the block hasn't
+    // been dealloc'd or reclaimed by a sweep since. A block the sweep in progress reclaimed is
+    // still known until the sweep is over
+    pub fn alloc_seq(addr: usize) -> Option<u64> {
+        get_recorder().live_seqs.get(&addr).copied()
+    }
+
+    pub fn flush() {
+        if let Some(out) = get_recorder().out.as_mut() {
+            let _ = out.flush();
+        }
+    }
+}
+
+#[cfg(feature = "trace")]
+pub use recorder::{alloc_seq, flush, forget_reclaimed, record, record_move};
+
+#[cfg(test)]
+mod tests {
+    use super::{read_trace, TraceOp, TraceRecord, RECORD_SIZE};
+
+    #[test]
+    fn trace_record_test() {
+        let records = vec![
+            TraceRecord {
+                op: TraceOp::Alloc,
+                size: 10,
+                addr: 0xdead_beef,
+                new_addr: 0,
+                time_ns: 1,
+            },
+            TraceRecord {
+                op: TraceOp::Dealloc,
+                size: 10,
+                addr: 0xdead_beef,
+                new_addr: 0,
+                time_ns: u64::MAX,
+            },
+            TraceRecord {
+                op: TraceOp::Sweep,
+                size: 0,
+                addr: 0,
+                new_addr: 0,
+                time_ns: 3,
+            },
+            TraceRecord {
+                op: TraceOp::Move,
+                size: 10,
+                addr: 0xdead_beef,
+                new_addr: 0x1000,
+                time_ns: 4,
+            },
+        ];
+
+        let mut out = vec![];
+        for r in &records {
+            r.write_to(&mut out).unwrap();
+        }
+        assert_eq!(out.len(), records.len() * RECORD_SIZE);
+
+        // Truncated record at the end is dropped
+        out.extend_from_slice(&[0, 1, 2]);
+        assert_eq!(read_trace(&mut out.as_slice()).unwrap(), records);
+
+        out[0] = 42;
+        assert!(read_trace(&mut out.as_slice()).is_err());
+    }
+}
@@ -46,6 +46,16 @@ pub fn get_actual_wosz_to_request(mut request_wo_sz: Wsize) -> Wsize {
     request_wo_sz
 }
 
+extern "C" {
+    fn atexit(cb: extern "C" fn()) -> std::ffi::c_int;
+}
+
+// Runs `cb` when the process exits normally(returning from main or calling exit). Returns false
+// if libc couldn't register it
+pub fn register_at_exit(cb: extern "C" fn()) -> bool {
+    unsafe { atexit(cb) == 0 }
+}
+
 #[inline(always)]
 pub fn get_layout(mem_size: Wsize) -> std::alloc::Layout {
     let next_pow_of_two = mem_size.to_bytesize().next_power_of_two();
@@ -53,6 +63,13 @@ pub fn get_layout(mem_size: Wsize) -> std::alloc::Layout {
     Layout::from_size_align(next_pow_of_two, ALIGN).unwrap()
 }
 
+// The layout a pool of `pool_wo_sz` words was allocated with, pool sizes are always what
+// get_layout gave
+#[inline(always)]
+pub fn get_pool_layout(pool_wo_sz: Wsize) -> std::alloc::Layout {
+    Layout::from_size_align(pool_wo_sz.to_bytesize(), ALIGN).unwrap()
+}
+
 #[inline(always)]
 pub fn get_layout_and_actual_expansion_size(mut request_wo_sz: Wsize) -> (Layout, Wsize) {
     request_wo_sz = get_actual_wosz_to_request(request_wo_sz);
@@ -182,10 +199,10 @@ pub fn try_merge(prev: Value, cur: Value) -> bool {
 
 #[test]
 pub fn field_val_test() {
-    let mem = field_val(Value(std::ptr::null_mut() as *mut u8 as usize), 1).0 as *mut u8;
+    let mem = field_val(Value(std::ptr::null_mut::<u8>() as usize), 1).0 as *mut u8;
     assert_eq!(field_val(Value(mem as usize), -1), Value(0));
     assert_eq!(
-        field_val(Value(std::ptr::null_mut() as *mut u8 as usize), 1),
+        field_val(Value(std::ptr::null_mut::<u8>() as usize), 1),
         Value(8)
     );
 }
@@ -0,0 +1,297 @@
+use std::fmt::Write;
+
+use crate::{
+    colors::{color_from_name, Color, CAML_BLACK, CAML_BLUE, CAML_GRAY, CAML_WHITE},
+    freelist::allocator::get_global_allocator,
+    utils::WORD_SIZE,
+};
+
+// A heap as it was written out by heap_dump
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DumpPool {
+    pub addr: usize,
+    pub pool_wo_sz: usize,
+    pub blocks: Vec<DumpBlock>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DumpBlock {
+    pub addr: usize,
+    pub wosize: usize,
+    pub color: Color,
+    pub tag: u8,
+    pub in_free_list: bool,
+}
+
+impl DumpBlock {
+    fn whsize(&self) -> usize {
+        self.wosize + 1
+    }
+    fn is_fragment(&self) -> bool {
+        self.wosize == 0 && self.color != CAML_BLUE
+    }
+}
+
+// Only understands the flat objects that heap_dump writes, it's not a general JSON parser
+fn field<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    let start = line.find(&format!("\"{key}\":"))? + key.len() + 3;
+    let rest = &line[start..];
+    let end = rest.find([',', '}']).unwrap_or(rest.len());
+    Some(rest[..end].trim_matches('"'))
+}
+
+fn parsed_field<T: std::str::FromStr>(line: &str, key: &str, line_no: usize) -> Result<T, String> {
+    field(line, key)
+        .and_then(|v| v.parse::<T>().ok())
+        .ok_or_else(|| format!("line {line_no}: missing or invalid \"{key}\""))
+}
+
+pub fn parse_dump(input: &str) -> Result<Vec<DumpPool>, String> {
+    let mut pools: Vec<DumpPool> = vec![];
+
+    for (i, line) in input
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty())
+    {
+        let line_no = i + 1;
+        match field(line, "type") {
+            Some("pool") => pools.push(DumpPool {
+                addr: parsed_field(line, "addr", line_no)?,
+                pool_wo_sz: parsed_field(line, "pool_wo_sz", line_no)?,
+                blocks: vec![],
+            }),
+            Some("block") => {
+                let block = DumpBlock {
+                    addr: parsed_field(line, "addr", line_no)?,
+                    wosize: parsed_field(line, "wosize", line_no)?,
+                    color: field(line, "color")
+                        .and_then(color_from_name)
+                        .ok_or_else(|| format!("line {line_no}: missing or invalid \"color\""))?,
+                    tag: parsed_field(line, "tag", line_no)?,
+                    in_free_list: parsed_field(line, "in_free_list", line_no)?,
+                };
+                let pool = pools
+                    .last_mut()
+                    .ok_or_else(|| format!("line {line_no}: block before any pool"))?;
+                // The header and the fields have to be inside of the pool
+                let pool_end = pool
+                    .addr
+                    .saturating_add(pool.pool_wo_sz.saturating_mul(WORD_SIZE));
+                let block_end = block
+                    .wosize
+                    .checked_mul(WORD_SIZE)
+                    .and_then(|sz| block.addr.checked_add(sz));
+                if block.addr < pool.addr.saturating_add(WORD_SIZE)
+                    || block_end.is_none_or(|end| end > pool_end)
+                {
+                    return Err(format!("line {line_no}: block outside of its pool"));
+                }
+                pool.blocks.push(block);
+            }
+            _ => return Err(format!("line {line_no}: unknown entry")),
+        }
+    }
+    Ok(pools)
+}
+
+// Dumps the global allocator and parses it back, for looking at the heap of the running process
+pub fn dump_current_heap() -> Vec<DumpPool> {
+    let mut out = vec![];
+    get_global_allocator()
+        .heap_dump(&mut out)
+        .expect("Writing to a Vec can't fail");
+    parse_dump(&String::from_utf8(out).expect("heap_dump only writes ASCII"))
+        .expect("heap_dump output must be parseable")
+}
+
+const ANSI_RESET: &str = "\x1b[0m";
+
+fn ansi_cell(block: &DumpBlock) -> (&'static str, char) {
+    if block.is_fragment() {
+        return ("\x1b[41m", '!');
+    }
+    match block.color {
+        CAML_BLUE if block.in_free_list => ("\x1b[44m", '.'),
+        // Free but not reachable from the free list, shouldn't ever be seen
+        CAML_BLUE => ("\x1b[45m", '?'),
+        CAML_BLACK => ("\x1b[40;97m", '#'),
+        CAML_WHITE => ("\x1b[47;30m", 'o'),
+        CAML_GRAY => ("\x1b[100m", 'g'),
+        _ => ("", '?'),
+    }
+}
+
+// Draws every pool as rows of `width` cells, each cell standing for `words_per_cell` words.
+// Blocks smaller than a cell still get one so that no block goes missing from the map.
+//
+// . free   # black   o white   ! zero sized fragment   g gray
+pub fn render_ansi(pools: &[DumpPool], width: usize, words_per_cell: usize) -> String {
+    let width = width.max(1);
+    let words_per_cell = words_per_cell.max(1);
+    let mut out = String::new();
+
+    for pool in pools {
+        let _ = writeln!(
+            out,
+            "pool {:#x}: {} words, {} blocks",
+            pool.addr,
+            pool.pool_wo_sz,
+            pool.blocks.len()
+        );
+        let mut col = 0;
+        for block in &pool.blocks {
+            let (style, ch) = ansi_cell(block);
+            let cells = block.whsize().div_ceil(words_per_cell).max(1);
+            out.push_str(style);
+            for _ in 0..cells {
+                out.push(ch);
+                col += 1;
+                if col == width {
+                    out.push_str(ANSI_RESET);
+                    out.push('\n');
+                    out.push_str(style);
+                    col = 0;
+                }
+            }
+            out.push_str(ANSI_RESET);
+        }
+        if col != 0 {
+            out.push('\n');
+        }
+    }
+    out
+}
+
+fn svg_fill(block: &DumpBlock) -> &'static str {
+    if block.is_fragment() {
+        return "#d62728";
+    }
+    match block.color {
+        CAML_BLUE if block.in_free_list => "#1f77b4",
+        CAML_BLUE => "#e377c2",
+        CAML_BLACK => "#222222",
+        CAML_WHITE => "#dddddd",
+        _ => "#888888",
+    }
+}
+
+// One horizontal bar per pool, `width` pixels wide, with each block placed at its offset inside
+// the pool. Zero sized fragments are drawn as thin red lines on top of the bar.
+pub fn render_svg(pools: &[DumpPool], width: usize) -> String {
+    const ROW_HEIGHT: usize = 24;
+    const LABEL_HEIGHT: usize = 16;
+    let row = ROW_HEIGHT + LABEL_HEIGHT;
+    let width = width.max(1) as f64;
+
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" font-family="monospace" font-size="12">"#,
+        width,
+        pools.len() * row
+    );
+    for (i, pool) in pools.iter().enumerate() {
+        let y = i * row;
+        let _ = writeln!(
+            out,
+            r#"<text x="0" y="{}">pool {:#x}: {} words, {} blocks</text>"#,
+            y + LABEL_HEIGHT - 4,
+            pool.addr,
+            pool.pool_wo_sz,
+            pool.blocks.len()
+        );
+
+        let scale = width / pool.pool_wo_sz.max(1) as f64;
+        for block in &pool.blocks {
+            // Offset in words of the header from the start of the pool, addr of a block is 1 word
+            // past its header
+            let offs = (block.addr - pool.addr) / WORD_SIZE - 1;
+            let x = offs as f64 * scale;
+            let _ = writeln!(
+                out,
+                r#"<rect x="{:.2}" y="{}" width="{:.2}" height="{}" fill="{}"><title>{:#x} wosize={} tag={}</title></rect>"#,
+                x,
+                y + LABEL_HEIGHT,
+                (block.whsize() as f64 * scale).max(if block.is_fragment() { 1.0 } else { 0.5 }),
+                ROW_HEIGHT,
+                svg_fill(block),
+                block.addr,
+                block.wosize,
+                block.tag
+            );
+        }
+    }
+    out.push_str("</svg>\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::colors::{CAML_BLACK, CAML_BLUE, CAML_WHITE};
+
+    use super::{parse_dump, render_ansi, render_svg, DumpBlock, DumpPool};
+
+    const DUMP: &str = r#"{"type":"pool","addr":4096,"pool_wo_sz":16}
+{"type":"block","addr":4136,"wosize":4,"color":"Blue","tag":0,"in_free_list":true}
+{"type":"block","addr":4176,"wosize":0,"color":"White","tag":0,"in_free_list":false}
+{"type":"block","addr":4184,"wosize":5,"color":"Black","tag":3,"in_free_list":false}
+"#;
+
+    #[test]
+    fn parse_and_render_test() {
+        let pools = parse_dump(DUMP).unwrap();
+        assert_eq!(
+            pools,
+            vec![DumpPool {
+                addr: 4096,
+                pool_wo_sz: 16,
+                blocks: vec![
+                    DumpBlock {
+                        addr: 4136,
+                        wosize: 4,
+                        color: CAML_BLUE,
+                        tag: 0,
+                        in_free_list: true
+                    },
+                    DumpBlock {
+                        addr: 4176,
+                        wosize: 0,
+                        color: CAML_WHITE,
+                        tag: 0,
+                        in_free_list: false
+                    },
+                    DumpBlock {
+                        addr: 4184,
+                        wosize: 5,
+                        color: CAML_BLACK,
+                        tag: 3,
+                        in_free_list: false
+                    },
+                ]
+            }]
+        );
+
+        let ansi = render_ansi(&pools, 80, 1);
+        let cells = ansi
+            .chars()
+            .filter(|c| ".!#".contains(*c))
+            .collect::<String>();
+        assert_eq!(cells, ".....!######");
+
+        let svg = render_svg(&pools, 160);
+        assert_eq!(svg.matches("<rect").count(), 3);
+
+        assert!(parse_dump(r#"{"type":"block","addr":1}"#).is_err());
+        assert!(parse_dump("garbage").is_err());
+        // Blocks that aren't inside of the pool before them
+        let pool = r#"{"type":"pool","addr":4096,"pool_wo_sz":16}"#;
+        for block in [
+            r#"{"type":"block","addr":4096,"wosize":1,"color":"White","tag":0,"in_free_list":false}"#,
+            r#"{"type":"block","addr":8,"wosize":1,"color":"White","tag":0,"in_free_list":false}"#,
+            r#"{"type":"block","addr":4184,"wosize":6,"color":"White","tag":0,"in_free_list":false}"#,
+        ] {
+            assert!(parse_dump(&format!("{pool}\n{block}")).is_err());
+        }
+    }
+}